@@ -0,0 +1,190 @@
+use std::cmp::Ordering;
+
+use crate::{
+    smoothing::Smoother, Backoff, MarkovGeneratorBase, Model, State, StateIndex, TokenId,
+    SENTENCE_END, SENTENCE_START,
+};
+
+/// A sequence of tokens found by [`MarkovGeneratorBase::beam_search`].
+pub struct Continuation {
+    pub tokens: Vec<TokenId>,
+    /// Sum of the natural log of the probability of each token.
+    pub log_prob: f64,
+}
+
+/// A continuation being searched, and the state it ended up in.
+struct Beam {
+    state: State,
+    continuation: Continuation,
+    /// Number of tokens in the continuation, not counting sentence markers.
+    length: u32,
+}
+
+/// A beam, the token to extend it with if any, and the log probability that
+/// would give it.
+type Candidate = (usize, Option<TokenId>, f64);
+
+/// Most likely candidates first, then in the order they were found.
+fn compare(a: &Candidate, b: &Candidate) -> Ordering {
+    b.2.total_cmp(&a.2).then(a.0.cmp(&b.0)).then(a.1.cmp(&b.1))
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Find the most likely continuations of `initial_state` up to `length`
+    /// tokens long, not counting sentence markers, keeping the `width` best
+    /// candidates after each step. They are returned best first.
+    pub fn beam_search(
+        &self,
+        initial_state: &State,
+        width: usize,
+        length: u32,
+        backoff: Backoff,
+        smoother: &Smoother,
+    ) -> Vec<Continuation> {
+        let markers = [SENTENCE_START, SENTENCE_END].map(|marker| self.token_id(marker));
+        let mut beams = vec![Beam {
+            state: initial_state.clone(),
+            continuation: Continuation {
+                tokens: Vec::new(),
+                log_prob: 0.0,
+            },
+            length: 0,
+        }];
+
+        loop {
+            let mut candidates: Vec<Candidate> = Vec::new();
+            let mut extended = false;
+            for (i, beam) in beams.iter().enumerate() {
+                let successors = if beam.length < length {
+                    self.successors(&beam.state, backoff, smoother)
+                } else {
+                    Vec::new()
+                };
+                if successors.is_empty() {
+                    // Long enough or a dead end, this continuation is finished
+                    candidates.push((i, None, beam.continuation.log_prob));
+                    continue;
+                }
+
+                extended = true;
+                let total: f64 = successors.iter().map(|&(_, w)| w).sum();
+                for (token, weight) in successors {
+                    let log_prob = beam.continuation.log_prob + (weight / total).ln();
+                    candidates.push((i, Some(token), log_prob));
+                }
+            }
+            if !extended {
+                break;
+            }
+
+            // Only the best few are kept, so only build the tokens and states of
+            // those
+            if candidates.len() > width && width > 0 {
+                candidates.select_nth_unstable_by(width - 1, compare);
+            }
+            candidates.truncate(width);
+            candidates.sort_by(compare);
+
+            beams = candidates
+                .into_iter()
+                .map(|(i, token, log_prob)| {
+                    let beam = &beams[i];
+                    let mut tokens = beam.continuation.tokens.clone();
+                    let mut state = beam.state.clone();
+                    let mut length = beam.length;
+                    if let Some(token) = token {
+                        tokens.push(token);
+                        let mut next_state = state.to_vec();
+                        next_state.push(token);
+                        state = State::from_slice(&next_state, self.state_size as usize);
+                        if !markers.contains(&Some(token)) {
+                            length += 1;
+                        }
+                    }
+                    Beam {
+                        state,
+                        continuation: Continuation { tokens, log_prob },
+                        length,
+                    }
+                })
+                .collect();
+        }
+
+        beams.into_iter().map(|beam| beam.continuation).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        smoothing::Smoothing,
+        tests::{state_of, trained},
+    };
+
+    #[test]
+    fn continuations_are_ranked_by_their_probability() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        let state = state_of(&markov, &["to", "be"]);
+
+        let continuations = markov.beam_search(&state, 5, 4, Backoff::Longest, &smoother);
+        assert_eq!(continuations.len(), 5);
+        for pair in continuations.windows(2) {
+            assert!(pair[0].log_prob >= pair[1].log_prob);
+        }
+
+        // Each one's probability is that of every token following the last
+        for continuation in &continuations {
+            let mut state = state.clone();
+            let mut log_prob = 0.0;
+            for &token in &continuation.tokens {
+                let successors = markov.successors(&state, Backoff::Longest, &smoother);
+                let total: f64 = successors.iter().map(|&(_, w)| w).sum();
+                let weight = successors.iter().find(|&&(t, _)| t == token).unwrap().1;
+                log_prob += (weight / total).ln();
+                let mut next = state.to_vec();
+                next.push(token);
+                state = State::from_slice(&next, 2);
+            }
+            assert!((continuation.log_prob - log_prob).abs() < 1e-9);
+        }
+
+        // A wide enough beam considers every continuation, so can't do worse
+        let wide = markov.beam_search(&state, 1000, 4, Backoff::Longest, &smoother);
+        assert!(wide[0].log_prob >= continuations[0].log_prob);
+    }
+
+    #[test]
+    fn sentence_markers_dont_count_towards_the_length() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        // Ends a sentence, so the continuations cross into the next one
+        let state = state_of(&markov, &["to", "dream"]);
+        let markers = [SENTENCE_START, SENTENCE_END].map(|m| markov.token_id(m).unwrap());
+
+        let continuations = markov.beam_search(&state, 3, 3, Backoff::Longest, &smoother);
+        assert!(continuations
+            .iter()
+            .any(|c| c.tokens.iter().any(|t| markers.contains(t))));
+        for continuation in &continuations {
+            let words = continuation.tokens.iter().filter(|t| !markers.contains(t));
+            assert_eq!(words.count(), 3);
+        }
+    }
+
+    #[test]
+    fn backoff_none_ends_continuations_at_unseen_states() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        let state = state_of(&markov, &["nobler", "to"]);
+
+        let none = markov.beam_search(&state, 3, 3, Backoff::None, &smoother);
+        assert!(none.len() == 1 && none[0].tokens.is_empty());
+        let longest = markov.beam_search(&state, 3, 3, Backoff::Longest, &smoother);
+        assert!(longest.iter().all(|c| c.tokens.len() == 3));
+    }
+}
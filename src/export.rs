@@ -0,0 +1,288 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{self, Write},
+};
+
+use crate::{Count, MarkovGeneratorBase, State, StateIndex};
+
+/// Which part of the transition matrix to include in a [`Graph`].
+pub struct GraphOptions {
+    /// Leave out transitions which happened fewer times than this.
+    pub min_count: Count,
+    /// Include at most this many states. The states reachable in the fewest
+    /// steps are kept when starting from a state, otherwise the most frequent.
+    pub max_nodes: Option<usize>,
+    /// Only include states reachable from this state in at most this many steps.
+    pub from: Option<(State, usize)>,
+}
+
+/// A subset of the states of a generator and the transitions between them.
+pub struct Graph {
+    /// Indices of the states in the state index.
+    pub nodes: Vec<usize>,
+    /// Transitions between the states in `nodes`, as `(from, to, count)`.
+    pub edges: Vec<(usize, usize, Count)>,
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Select the full sized states and transitions described by `options`.
+    /// Returns `None` if starting from a state which isn't known.
+    pub fn transition_graph(&self, options: &GraphOptions) -> Option<Graph> {
+        let chain = self.chain();
+        let successors = |state: usize| {
+            let row = chain.mat.outer_view(state).unwrap();
+            row.iter()
+                .filter(|&(_, &count)| count >= options.min_count)
+                .map(|(to, &count)| (to, count))
+                .collect::<Vec<_>>()
+        };
+
+        let mut nodes = match &options.from {
+            Some((state, depth)) => {
+                // Breadth first, so the closest states come first
+                let start = chain.states.get_index(state)?;
+                let mut nodes = vec![start];
+                let mut seen = HashSet::from([start]);
+                let mut queue = VecDeque::from([(start, 0)]);
+                while let Some((state, distance)) = queue.pop_front() {
+                    if distance == *depth {
+                        continue;
+                    }
+                    for (next, _) in successors(state) {
+                        if seen.insert(next) {
+                            nodes.push(next);
+                            queue.push_back((next, distance + 1));
+                        }
+                    }
+                }
+                nodes
+            }
+            None => {
+                // Only states with a transition left after filtering, most frequent first
+                let mut connected = HashSet::new();
+                for (&count, (from, to)) in chain.mat.iter() {
+                    if count >= options.min_count {
+                        connected.insert(from);
+                        connected.insert(to);
+                    }
+                }
+
+                let mut nodes: Vec<(usize, u64)> = connected
+                    .into_iter()
+                    .map(|i| {
+                        let row = chain.mat.outer_view(i).unwrap();
+                        (i, row.data().iter().sum())
+                    })
+                    .collect();
+                nodes.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+                nodes.into_iter().map(|(i, _)| i).collect()
+            }
+        };
+
+        if let Some(max_nodes) = options.max_nodes {
+            nodes.truncate(max_nodes);
+        }
+
+        let included: HashSet<usize> = nodes.iter().copied().collect();
+        let edges = nodes
+            .iter()
+            .flat_map(|&from| {
+                successors(from)
+                    .into_iter()
+                    .filter(|(to, _)| included.contains(to))
+                    .map(move |(to, count)| (from, to, count))
+            })
+            .collect();
+
+        Some(Graph { nodes, edges })
+    }
+
+    fn state_label(&self, index: usize) -> String {
+        let state = self.chain().states.get_state(index).unwrap();
+        self.state_tokens(state).collect::<Vec<_>>().join(" ")
+    }
+
+    /// Write `graph` in Graphviz's DOT format, with transition counts as edge
+    /// weights.
+    pub fn write_dot<W: Write>(&self, graph: &Graph, writer: &mut W) -> io::Result<()> {
+        fn escape(label: &str) -> String {
+            label.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        writeln!(writer, "digraph markov {{")?;
+        for &node in &graph.nodes {
+            writeln!(
+                writer,
+                "    n{} [label=\"{}\"];",
+                node,
+                escape(&self.state_label(node))
+            )?;
+        }
+        for &(from, to, count) in &graph.edges {
+            writeln!(
+                writer,
+                "    n{from} -> n{to} [weight={count}, label=\"{count}\"];"
+            )?;
+        }
+        writeln!(writer, "}}")
+    }
+
+    /// Write `graph` as GraphML, with each state's tokens in a `label` attribute
+    /// and transition counts in a `weight` attribute.
+    pub fn write_graphml<W: Write>(&self, graph: &Graph, writer: &mut W) -> io::Result<()> {
+        fn escape(label: &str) -> String {
+            let mut escaped = String::with_capacity(label.len());
+            for c in label.chars() {
+                match c {
+                    '&' => escaped.push_str("&amp;"),
+                    '<' => escaped.push_str("&lt;"),
+                    '>' => escaped.push_str("&gt;"),
+                    '"' => escaped.push_str("&quot;"),
+                    '\'' => escaped.push_str("&apos;"),
+                    _ => escaped.push(c),
+                }
+            }
+            escaped
+        }
+
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            writer,
+            r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="label" for="node" attr.name="label" attr.type="string"/>"#
+        )?;
+        writeln!(
+            writer,
+            r#"  <key id="weight" for="edge" attr.name="weight" attr.type="long"/>"#
+        )?;
+        writeln!(writer, r#"  <graph id="markov" edgedefault="directed">"#)?;
+        for &node in &graph.nodes {
+            writeln!(writer, r#"    <node id="n{node}">"#)?;
+            writeln!(
+                writer,
+                r#"      <data key="label">{}</data>"#,
+                escape(&self.state_label(node))
+            )?;
+            writeln!(writer, r#"    </node>"#)?;
+        }
+        for &(from, to, count) in &graph.edges {
+            writeln!(writer, r#"    <edge source="n{from}" target="n{to}">"#)?;
+            writeln!(writer, r#"      <data key="weight">{count}</data>"#)?;
+            writeln!(writer, r#"    </edge>"#)?;
+        }
+        writeln!(writer, "  </graph>")?;
+        writeln!(writer, "</graphml>")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tokenizer::Words, MarkovGenerator, Model, SENTENCE_START};
+
+    /// A generator trained as <s> a b a b a c . </s>, with states numbered in
+    /// that order.
+    fn generator() -> MarkovGenerator {
+        let mut markov = MarkovGenerator::new(1);
+        markov
+            .train(&mut "A b a b a c.".as_bytes(), &Words)
+            .unwrap();
+        markov
+    }
+
+    fn options() -> GraphOptions {
+        GraphOptions {
+            min_count: 1,
+            max_nodes: None,
+            from: None,
+        }
+    }
+
+    #[test]
+    fn rare_transitions_are_left_out() {
+        let markov = generator();
+        let all = markov.transition_graph(&options()).unwrap();
+        assert_eq!(all.nodes.len(), 6);
+        assert_eq!(all.edges.len(), 6);
+
+        let frequent = GraphOptions {
+            min_count: 2,
+            ..options()
+        };
+        let graph = markov.transition_graph(&frequent).unwrap();
+        // "a" was followed by something more often than "b"
+        assert_eq!(graph.nodes, [1, 2]);
+        assert_eq!(graph.edges, [(1, 2, 2), (2, 1, 2)]);
+    }
+
+    #[test]
+    fn graphs_from_a_state_keep_the_closest_states() {
+        let markov = generator();
+        let start = State::new([markov.token_id(SENTENCE_START).unwrap()].into());
+
+        let from = GraphOptions {
+            from: Some((start.clone(), 2)),
+            ..options()
+        };
+        let graph = markov.transition_graph(&from).unwrap();
+        assert_eq!(graph.nodes, [0, 1, 2, 3]);
+        assert_eq!(graph.edges, [(0, 1, 1), (1, 2, 2), (1, 3, 1), (2, 1, 2)]);
+
+        let limited = GraphOptions {
+            from: Some((start, 2)),
+            max_nodes: Some(2),
+            ..options()
+        };
+        let graph = markov.transition_graph(&limited).unwrap();
+        assert_eq!(graph.nodes, [0, 1]);
+        assert_eq!(graph.edges, [(0, 1, 1)]);
+
+        let unknown = GraphOptions {
+            from: Some((State::new([100].into()), 2)),
+            ..options()
+        };
+        assert!(markov.transition_graph(&unknown).is_none());
+    }
+
+    #[test]
+    fn dot_lists_the_states_and_transitions() {
+        let markov = generator();
+        let frequent = GraphOptions {
+            min_count: 2,
+            ..options()
+        };
+        let graph = markov.transition_graph(&frequent).unwrap();
+        let mut dot = Vec::new();
+        markov.write_dot(&graph, &mut dot).unwrap();
+
+        assert_eq!(
+            String::from_utf8(dot).unwrap(),
+            "digraph markov {\n    \
+                 n1 [label=\"a\"];\n    \
+                 n2 [label=\"b\"];\n    \
+                 n1 -> n2 [weight=2, label=\"2\"];\n    \
+                 n2 -> n1 [weight=2, label=\"2\"];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn graphml_escapes_labels() {
+        let markov = generator();
+        let graph = markov.transition_graph(&options()).unwrap();
+        let mut graphml = Vec::new();
+        markov.write_graphml(&graph, &mut graphml).unwrap();
+        let graphml = String::from_utf8(graphml).unwrap();
+
+        assert!(graphml.contains(r#"<data key="label">&lt;s&gt;</data>"#));
+        assert!(graphml.contains(r#"<edge source="n1" target="n2">"#));
+        assert_eq!(graphml.matches("<node ").count(), 6);
+        assert_eq!(graphml.matches("<edge ").count(), 6);
+    }
+}
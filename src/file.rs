@@ -0,0 +1,380 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mapped::{read_mapped, write_mapped, MAPPED_MAGIC_FILE_BYTES},
+    smoothing::Smoothing,
+    Chain, Count, CountWidth, MarkovGenerator, MarkovGeneratorBase, Metadata, State, StateIndex,
+    TokenizerOptions, TransitionCounts, Vocabulary,
+};
+
+/// Magic numbers prefixed to saved transition matrix files, so we can detect
+/// them more easily. They are followed by the format version, so these don't
+/// need to change when the format does.
+static MAGIC_FILE_BYTES: [u8; 3] = [0x3, 0x4, 0x6];
+
+/// Version of the checksum, header and payload written after the magic numbers.
+/// Files with a newer version than this are rejected.
+const FORMAT_VERSION: u16 = 1;
+
+/// Magic numbers of files saved before the format was versioned, which only had
+/// the matrix, states and state size. Their states stored their own copy of
+/// their tokens, and their transitions were `(next state, previous state)`.
+static LEGACY_MAGIC_FILE_BYTES: [u8; 3] = [0x3, 0x4, 0x5];
+
+/// Written before the generator itself, describing how it was trained.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Header {
+    pub state_size: u32,
+    pub tokenizer: TokenizerOptions,
+    /// Number of tokens the generator was trained on, including sentence markers.
+    pub tokens: u64,
+    /// Number of full sized states.
+    pub states: u64,
+    /// When the generator was first trained, in seconds since the Unix epoch.
+    pub created: u64,
+    /// Names of the files the generator was trained on.
+    pub sources: Vec<String>,
+    pub count_width: CountWidth,
+    pub smoothing: Smoothing,
+}
+
+impl Header {
+    pub fn new(markov: &MarkovGenerator) -> Self {
+        Self {
+            state_size: markov.state_size,
+            tokenizer: markov.metadata.tokenizer.clone(),
+            tokens: markov.metadata.tokens,
+            states: markov.chain().states.len() as u64,
+            created: markov.metadata.created,
+            sources: markov.metadata.sources.clone(),
+            count_width: markov.metadata.count_width,
+            smoothing: markov.metadata.smoothing,
+        }
+    }
+
+    pub fn into_metadata(self) -> Metadata {
+        Metadata {
+            tokenizer: self.tokenizer,
+            tokens: self.tokens,
+            created: self.created,
+            sources: self.sources,
+            count_width: self.count_width,
+            smoothing: self.smoothing,
+        }
+    }
+}
+
+/// Layout of legacy generators, where every state held its own tokens and
+/// only the full order chain was saved.
+#[derive(Deserialize)]
+struct LegacyGenerator {
+    mat: sprs::CsMat<Count>,
+    states: Vec<Vec<String>>,
+    state_size: u32,
+}
+
+impl<S> Chain<S>
+where
+    S: StateIndex + Default,
+{
+    /// Legacy files sized the matrix for the most states the text could have had
+    /// rather than how many it had. Shrink it to fit the states, unless some
+    /// transition is outside of them.
+    fn fit_matrix(&mut self) {
+        let n_states = self.states.len();
+        let fits = self
+            .mat
+            .iter()
+            .all(|(_, (from, to))| from < n_states && to < n_states);
+        if self.mat.shape() != (n_states, n_states) && fits {
+            self.mat = TransitionCounts::from_matrix(&self.mat, Count::MAX).into_matrix(n_states);
+        }
+    }
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Convert a legacy generator, interning its tokens and transposing its
+    /// matrix. The lower orders are derived from the full order chain, which
+    /// misses the first few transitions of the training text but is otherwise
+    /// the same as training them.
+    fn from_legacy(legacy: LegacyGenerator) -> Self {
+        let mut vocab = Vocabulary::default();
+        let mut states: S = Default::default();
+        for (i, tokens) in legacy.states.iter().enumerate() {
+            let ids = tokens.iter().map(|t| vocab.intern(t)).collect();
+            states.insert(i, State::new(ids));
+        }
+
+        let mut chain = Chain {
+            mat: legacy.mat.transpose_into().into_csr(),
+            states,
+        };
+        chain.fit_matrix();
+        let mut chains = vec![chain];
+        for _ in 1..legacy.state_size {
+            let lower = chains
+                .last()
+                .unwrap()
+                .derive_lower_order(CountWidth::U16.max_count());
+            chains.push(lower);
+        }
+        chains.reverse();
+
+        let metadata = Metadata::from_vocabulary(&vocab);
+        Self {
+            vocab,
+            chains,
+            state_size: legacy.state_size,
+            metadata,
+        }
+    }
+}
+
+pub fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Error for a file with a format `version` other than `supported`.
+pub fn unsupported_version(version: u16, supported: u16) -> io::Error {
+    if version > supported {
+        invalid_data(format!(
+            "Unsupported matrix file version {version}, the newest supported version is \
+             {supported}. It was saved by a newer version of this program"
+        ))
+    } else {
+        invalid_data(format!(
+            "Invalid matrix file: unknown format version {version}"
+        ))
+    }
+}
+
+/// Check that `payload` has the checksum it was saved with.
+pub fn verify_checksum(expected: u32, payload: &[u8]) -> io::Result<()> {
+    let actual = crc32fast::hash(payload);
+    if actual != expected {
+        return Err(invalid_data(format!(
+            "Invalid matrix file: checksum mismatch, expected {expected:08x} but the contents \
+             have {actual:08x}. The file is truncated or corrupted"
+        )));
+    }
+    Ok(())
+}
+
+fn invalid_matrix(e: postcard::Error) -> io::Error {
+    invalid_data(format!("Invalid matrix file: {e}"))
+}
+
+/// Load a saved transition matrix from `reader`, read from `path` if it's a file.
+/// Returns `None` without consuming anything if the input isn't a matrix file.
+/// JSON matrices are only recognised by a `.json` extension, as text can start
+/// like JSON too.
+pub fn read_model<R: BufRead>(
+    reader: &mut R,
+    path: Option<&Path>,
+) -> io::Result<Option<MarkovGenerator>> {
+    if path.is_some_and(|path| path.extension().is_some_and(|ext| ext == "json")) {
+        let json = serde_json::from_reader(reader)
+            .map_err(|e| invalid_data(format!("Invalid JSON matrix: {e}")))?;
+        let markov = MarkovGenerator::from_json(json)
+            .map_err(|e| invalid_data(format!("Invalid JSON matrix: {e}")))?;
+        return Ok(Some(markov));
+    }
+
+    let file_preview = reader.fill_buf()?;
+    let markov = if file_preview.starts_with(&MAGIC_FILE_BYTES) {
+        reader.consume(MAGIC_FILE_BYTES.len()); // Skip magic bytes
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        read_versioned(&buf)?
+    } else if file_preview.starts_with(&LEGACY_MAGIC_FILE_BYTES) {
+        reader.consume(MAGIC_FILE_BYTES.len());
+
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        let legacy = postcard::from_bytes(&buf).map_err(invalid_matrix)?;
+        MarkovGenerator::from_legacy(legacy)
+    } else if file_preview.starts_with(&MAPPED_MAGIC_FILE_BYTES) {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+        read_mapped(buf)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(markov))
+}
+
+/// Read the version, header and generator following the magic numbers.
+fn read_versioned(buf: &[u8]) -> io::Result<MarkovGenerator> {
+    let Some((version, payload)) = buf.split_first_chunk() else {
+        return Err(invalid_data("Invalid matrix file: missing version".into()));
+    };
+
+    let version = u16::from_le_bytes(*version);
+    if version != FORMAT_VERSION {
+        return Err(unsupported_version(version, FORMAT_VERSION));
+    }
+    let Some((checksum, payload)) = payload.split_first_chunk() else {
+        return Err(invalid_data("Invalid matrix file: missing checksum".into()));
+    };
+    verify_checksum(u32::from_le_bytes(*checksum), payload)?;
+
+    let (header, payload): (Header, _) =
+        postcard::take_from_bytes(payload).map_err(invalid_matrix)?;
+    let mut markov: MarkovGenerator = postcard::from_bytes(payload).map_err(invalid_matrix)?;
+    if header.state_size != markov.state_size {
+        return Err(invalid_data(format!(
+            "Invalid matrix file: the header has a state size of {}, but the matrix has {}",
+            header.state_size, markov.state_size
+        )));
+    }
+    if let Some(chain) = markov.chains.last() {
+        if header.states != chain.states.len() as u64 {
+            return Err(invalid_data(format!(
+                "Invalid matrix file: the header has {} states, but the matrix has {}",
+                header.states,
+                chain.states.len()
+            )));
+        }
+    }
+
+    markov.metadata = header.into_metadata();
+    Ok(markov)
+}
+
+/// Save `markov` to `path`, as JSON if the file name ends in `.json`, in the
+/// memory mapped layout if it ends in `.mmap`, or in the binary format
+/// otherwise.
+pub fn write_model(path: &Path, markov: &MarkovGenerator) -> io::Result<()> {
+    if path.extension().is_some_and(|ext| ext == "json") {
+        let writer = io::BufWriter::new(File::create(path)?);
+        return Ok(serde_json::to_writer_pretty(writer, &markov.to_json())?);
+    } else if path.extension().is_some_and(|ext| ext == "mmap") {
+        return write_mapped(path, markov);
+    }
+
+    let mut payload = postcard::to_allocvec(&Header::new(markov)).unwrap();
+    payload.extend(postcard::to_allocvec(markov).unwrap());
+    let mut output_file = File::create(path)?;
+    output_file.write_all(&MAGIC_FILE_BYTES)?;
+    output_file.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    output_file.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    output_file.write_all(&payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{temp_path, trained, transitions};
+
+    /// Check that `markov` is the same after saving it to a temporary file named
+    /// `name` and reading it back.
+    fn assert_round_trips(name: &str) {
+        let mut markov = trained(3);
+        markov.metadata.smoothing = Smoothing::WittenBell(0.5);
+        markov.metadata.sources.push("hamlet.txt".into());
+        markov.metadata.created = 1_000_000_000;
+
+        let path = temp_path(name);
+        write_model(&path, &markov).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let loaded = read_model(&mut bytes.as_slice(), Some(&path))
+            .unwrap()
+            .unwrap();
+
+        let json = |markov: &MarkovGenerator| serde_json::to_string(&markov.to_json()).unwrap();
+        assert_eq!(json(&loaded), json(&markov));
+    }
+
+    #[test]
+    fn binary_matrices_round_trip() {
+        assert_round_trips("round-trip.bin");
+    }
+
+    #[test]
+    fn mapped_matrices_round_trip() {
+        assert_round_trips("round-trip.mmap");
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let path = temp_path("newer.bin");
+        write_model(&path, &trained(2)).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        bytes[MAGIC_FILE_BYTES.len()..][..2].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let error = read_model(&mut bytes.as_slice(), None).err().unwrap();
+        assert!(error.to_string().contains("newer version"), "{error}");
+    }
+
+    #[test]
+    fn corrupted_files_fail_their_checksum() {
+        let path = temp_path("corrupted.bin");
+        write_model(&path, &trained(2)).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        let error = read_model(&mut bytes.as_slice(), None).err().unwrap();
+        assert!(error.to_string().contains("checksum mismatch"), "{error}");
+
+        bytes.truncate(bytes.len() / 2);
+        assert!(read_model(&mut bytes.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn json_matrices_round_trip() {
+        assert_round_trips("round-trip.json");
+    }
+
+    #[test]
+    fn text_is_not_a_matrix() {
+        let mut text = "{ braces are text too }".as_bytes();
+        assert!(read_model(&mut text, Some(Path::new("brace.txt")))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn legacy_matrices_are_transposed() {
+        let markov = trained(2);
+        let chain = markov.chain();
+        let n_states = chain.states.len();
+
+        // Legacy files stored `(next state, previous state)` with 16-bit counts,
+        // in a matrix sized for more states than there were
+        let mut mat = sprs::TriMat::new((n_states + 5, n_states + 5));
+        for (&count, (from, to)) in chain.mat.iter() {
+            mat.add_triplet(to, from, count as u16);
+        }
+        let states: Vec<Vec<String>> = (0..n_states)
+            .map(|i| {
+                let state = chain.states.get_state(i).unwrap();
+                markov.state_tokens(state).map(str::to_owned).collect()
+            })
+            .collect();
+        let legacy: sprs::CsMat<u16> = mat.to_csr();
+        let mut bytes = LEGACY_MAGIC_FILE_BYTES.to_vec();
+        bytes.extend(postcard::to_allocvec(&(legacy, states, markov.state_size)).unwrap());
+
+        let loaded = read_model(&mut bytes.as_slice(), None).unwrap().unwrap();
+        assert_eq!(loaded.chain().mat.shape(), (n_states, n_states));
+        assert_eq!(transitions(&loaded), transitions(&markov));
+        assert_eq!(loaded.chains.len(), 2);
+        assert!(loaded.metadata.tokenizer.sentence_markers);
+        assert!(loaded.metadata.count_width == CountWidth::U16);
+    }
+}
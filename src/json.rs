@@ -0,0 +1,131 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Chain, Count, MarkovGeneratorBase, Metadata, State, StateIndex, TokenId, TransitionCounts,
+    Vocabulary,
+};
+
+/// A generator in a human readable form, for saving as JSON. States are written
+/// out as their tokens rather than ids, so the file can be read and edited by
+/// hand.
+#[derive(Serialize, Deserialize)]
+pub struct JsonGenerator {
+    pub state_size: u32,
+    pub metadata: Metadata,
+    /// Every token, in the order of their ids. Tokens used by states which are
+    /// missing from here are added to the end when loading.
+    pub vocabulary: Vec<String>,
+    /// One chain per order, from states of 1 token up to `state_size` tokens.
+    pub chains: Vec<JsonChain>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct JsonChain {
+    pub states: Vec<Vec<String>>,
+    /// Transitions as `[from, to, count]`, where `from` and `to` are indices into
+    /// `states`.
+    pub transitions: Vec<(usize, usize, Count)>,
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    pub fn to_json(&self) -> JsonGenerator {
+        let chains = self
+            .chains
+            .iter()
+            .map(|chain| JsonChain {
+                states: (0..chain.states.len())
+                    .map(|i| {
+                        let state = chain.states.get_state(i).unwrap();
+                        self.state_tokens(state).map(str::to_owned).collect()
+                    })
+                    .collect(),
+                transitions: chain
+                    .mat
+                    .iter()
+                    .map(|(&count, (from, to))| (from, to, count))
+                    .collect(),
+            })
+            .collect();
+
+        JsonGenerator {
+            state_size: self.state_size,
+            metadata: self.metadata.clone(),
+            vocabulary: self.vocab.iter().map(str::to_owned).collect(),
+            chains,
+        }
+    }
+
+    /// Build a generator from its JSON form, checking that it is consistent.
+    pub fn from_json(json: JsonGenerator) -> Result<Self, String> {
+        if json.state_size == 0 {
+            return Err("the state size must be at least 1".into());
+        }
+        if json.chains.len() != json.state_size as usize {
+            return Err(format!(
+                "expected {} chains for a state size of {}, found {}",
+                json.state_size,
+                json.state_size,
+                json.chains.len()
+            ));
+        }
+
+        let mut vocab = Vocabulary::default();
+        for token in &json.vocabulary {
+            vocab.intern(token);
+        }
+        if vocab.len() != json.vocabulary.len() {
+            return Err("the vocabulary contains duplicate tokens".into());
+        }
+
+        let count_width = json.metadata.count_width;
+        json.metadata.smoothing.validate()?;
+        let mut chains = Vec::with_capacity(json.chains.len());
+        for (i, json_chain) in json.chains.into_iter().enumerate() {
+            let order = i + 1;
+            let mut chain: Chain<S> = Chain::new();
+            for tokens in &json_chain.states {
+                if tokens.len() != order {
+                    return Err(format!(
+                        "chain {order} should only have states of {order} tokens, found {tokens:?}"
+                    ));
+                }
+
+                let ids: Box<[TokenId]> = tokens.iter().map(|t| vocab.intern(t)).collect();
+                let index = chain.states.len();
+                if chain.index_or_insert(State::new(ids)) != index {
+                    return Err(format!("chain {order} has the state {tokens:?} twice"));
+                }
+            }
+
+            let n_states = chain.states.len();
+            let mut counts = TransitionCounts::default();
+            for (from, to, count) in json_chain.transitions {
+                if from >= n_states || to >= n_states {
+                    return Err(format!(
+                        "chain {order} has a transition from {from} to {to}, but only \
+                         {n_states} states"
+                    ));
+                }
+                if count > count_width.max_count() {
+                    return Err(format!(
+                        "chain {order} has a count of {count}, which doesn't fit in {} bits",
+                        count_width.bits()
+                    ));
+                }
+                counts.add(from, to, count);
+            }
+            chain.mat = counts.into_matrix(n_states);
+            chains.push(chain);
+        }
+
+        Ok(Self {
+            vocab,
+            chains,
+            state_size: json.state_size,
+            metadata: json.metadata,
+        })
+    }
+}
@@ -0,0 +1,2174 @@
+//! Generate text with Markov chains. [`main`] runs the command line program,
+//! and programs wrapping it can make their own tokenizers available with
+//! [`tokenizer::register`] before calling it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+mod beam;
+mod export;
+mod file;
+mod json;
+mod mapped;
+mod prune;
+mod sampling;
+mod score;
+mod smoothing;
+mod stationary;
+mod stats;
+pub mod tokenizer;
+mod verify;
+
+use clap::{
+    builder::{PossibleValue, PossibleValuesParser},
+    error::ErrorKind,
+    CommandFactory, Parser, Subcommand, ValueEnum,
+};
+use export::GraphOptions;
+use file::{invalid_data, read_model, write_model};
+use indexmap::IndexSet;
+use mapped::MappedGenerator;
+use prune::PruneOptions;
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng, SeedableRng,
+};
+use rand_chacha::ChaCha8Rng;
+use sampling::SamplingConfig;
+use serde::{Deserialize, Serialize};
+use smoothing::{Smoother, Smoothing};
+use stationary::StationaryOptions;
+use tokenizer::{Detokenizer, Tokenizer};
+
+#[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// A text file containing text to learn off of, or a .bin, .json or .mmap
+    /// file containing a previously saved transition matrix.
+    input_file: Option<PathBuf>,
+    initial_phrase: Option<String>,
+
+    /// Number of tokens to generate, not counting the markers between sentences.
+    /// With `--sentences`, the maximum number of tokens in each sentence.
+    #[arg(short('s'), long, default_value_t = 200)]
+    output_size: u32,
+
+    /// Generate this many complete sentences instead of a fixed number of tokens.
+    /// The matrix must have been trained with sentence markers.
+    #[arg(long, value_name = "N", conflicts_with = "initial_phrase")]
+    sentences: Option<u32>,
+
+    /// The number of tokens to use per state in the transition matrix. Default
+    /// is 2, performance decreases exponentially when increased.
+    ///
+    /// When loading an existing matrix this must match the size it was trained with.
+    #[arg(short('t'), long, value_parser = clap::value_parser!(u32).range(1..))]
+    state_size: Option<u32>,
+
+    /// What to split the text into when training a new matrix. Loaded matrices
+    /// keep what they were trained with.
+    #[arg(long, value_parser = tokenizer_names())]
+    tokenizer: Option<String>,
+
+    /// Largest number of bits each transition count may need. Counts which would
+    /// need more stay at the largest one that fits. This caps the counts, and
+    /// sets how wide they are in `.mmap` files, but counts always take 64 bits
+    /// in memory. Defaults to 32; a loaded matrix can be widened but not
+    /// narrowed.
+    #[arg(long, value_enum)]
+    count_width: Option<CountWidth>,
+
+    #[command(flatten)]
+    prune: PruneArgs,
+
+    #[command(flatten)]
+    smoothing: SmoothingArgs,
+
+    #[command(flatten)]
+    backoff: BackoffArgs,
+
+    /// Reshape the probabilities of the next token. Below 1 makes likely tokens
+    /// more likely, above 1 makes the output more random. 0 always picks the most
+    /// likely token.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_non_negative)]
+    temperature: f64,
+
+    /// Only pick from the K most likely next tokens
+    #[arg(long, value_name = "K", value_parser = clap::value_parser!(u32).range(1..))]
+    top_k: Option<u32>,
+
+    /// Only pick from the most likely next tokens whose probabilities add up to P
+    #[arg(long, value_name = "P", value_parser = parse_probability)]
+    top_p: Option<f64>,
+
+    /// Seed for the random number generator. The same matrix and seed always
+    /// generate the same output.
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Print the seed used to stderr, so the output can be reproduced later
+    #[arg(long)]
+    print_seed: bool,
+
+    /// Additional text file to train on, can be given multiple times. When
+    /// loading an existing matrix, it is updated with the new text.
+    #[arg(long)]
+    train: Vec<PathBuf>,
+
+    /// Save transition matrix to a file instead of generating tokens. Files
+    /// ending in `.json` are saved as JSON, files ending in `.mmap` in a binary
+    /// format which is generated from without loading it into memory, and
+    /// anything else in a compact binary format. Either way the names of the
+    /// files trained on are recorded with it.
+    #[arg(long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "markov.bin")]
+    save: Option<PathBuf>,
+}
+
+/// The names of every registered tokenizer, for `--tokenizer`.
+fn tokenizer_names() -> PossibleValuesParser {
+    PossibleValuesParser::new(tokenizer::registered().into_iter().map(|registered| {
+        let value = PossibleValue::new(registered.name);
+        match registered.help {
+            Some(help) => value.help(help),
+            None => value,
+        }
+    }))
+}
+
+fn parse_non_negative(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value >= 0.0 => Ok(value),
+        Ok(_) => Err("must not be negative".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_probability(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value > 0.0 && value <= 1.0 => Ok(value),
+        Ok(_) => Err("must be above 0 and at most 1".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_weight(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if value.is_finite() && value > 0.0 => Ok(value),
+        Ok(_) => Err("must be a finite number above 0".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_teleport(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if (0.0..1.0).contains(&value) => Ok(value),
+        Ok(_) => Err("must be at least 0 and below 1".into()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// How to smooth probabilities, saved when training and used when generating or
+// scoring.
+#[derive(clap::Args)]
+struct SmoothingArgs {
+    /// How to assign probabilities to transitions which never happened while
+    /// training. One of `none`, `additive[:K]`, `witten-bell[:K]` or
+    /// `kneser-ney[:D]`. Saved with the matrix when training, and otherwise
+    /// defaults to the smoothing saved with it. Scoring uses `additive` if the
+    /// matrix has none.
+    #[arg(long)]
+    smoothing: Option<Smoothing>,
+}
+
+// How to fall back to shorter states, when generating or searching for
+// continuations.
+#[derive(clap::Args)]
+struct BackoffArgs {
+    /// What to do when the current state was never followed by anything while
+    /// training, without smoothing.
+    #[arg(long, value_enum, default_value_t = BackoffArg::Longest)]
+    backoff: BackoffArg,
+
+    /// How much to scale down the weights of each shorter state with
+    /// `--backoff=stupid`, above 0 and at most 1.
+    #[arg(long, default_value_t = 0.4, value_parser = parse_probability)]
+    backoff_factor: f64,
+}
+
+impl BackoffArgs {
+    fn backoff(&self) -> Backoff {
+        match self.backoff {
+            BackoffArg::None => Backoff::None,
+            BackoffArg::Longest => Backoff::Longest,
+            BackoffArg::Stupid => Backoff::Stupid(self.backoff_factor),
+        }
+    }
+}
+
+// Options for removing rare transitions, after training or from a saved matrix.
+#[derive(clap::Args)]
+struct PruneArgs {
+    /// Remove transitions seen fewer times than this
+    #[arg(long, default_value_t = 1)]
+    prune_min_count: Count,
+
+    /// Only keep this many of the most frequent successors of each state
+    #[arg(long)]
+    prune_max_successors: Option<usize>,
+
+    /// Also remove states which pruning left without any transitions leading to
+    /// them
+    #[arg(long)]
+    prune_unreachable: bool,
+}
+
+impl PruneArgs {
+    /// The options to prune with, or `None` if nothing would be pruned.
+    fn options(&self) -> Option<PruneOptions> {
+        (self.prune_min_count > 1 || self.prune_max_successors.is_some()).then_some(PruneOptions {
+            min_count: self.prune_min_count,
+            max_successors: self.prune_max_successors,
+            drop_unreachable: self.prune_unreachable,
+        })
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    Graphml,
+}
+
+/// How many bits each transition count of a generator is limited to. This is
+/// only a cap, in memory counts are always a [`Count`].
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum,
+)]
+enum CountWidth {
+    // The only width of legacy matrices
+    #[value(name = "16")]
+    U16,
+    #[default]
+    #[value(name = "32")]
+    U32,
+    #[value(name = "64")]
+    U64,
+}
+
+impl CountWidth {
+    fn bits(self) -> u32 {
+        match self {
+            CountWidth::U16 => 16,
+            CountWidth::U32 => 32,
+            CountWidth::U64 => 64,
+        }
+    }
+
+    /// The largest count which fits.
+    fn max_count(self) -> Count {
+        Count::MAX >> (Count::BITS - self.bits())
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum BackoffArg {
+    /// Jump to a random state
+    None,
+    /// Use the longest end of the state that has been followed by something
+    Longest,
+    /// Combine what followed every end of the state, preferring longer ones
+    Stupid,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Merge several saved transition matrices into one
+    Merge {
+        /// Saved transition matrix files to merge
+        #[arg(required = true)]
+        models: Vec<PathBuf>,
+
+        /// Weights to scale each matrix's counts by before summing, in the same
+        /// order as the files. Defaults to 1 for every matrix. Transitions are
+        /// kept with a count of at least 1, however small their weight.
+        #[arg(short, long, value_delimiter = ',', value_parser = parse_weight)]
+        weights: Vec<f64>,
+
+        /// File to save the merged transition matrix to
+        #[arg(long, default_value = "markov.bin")]
+        save: PathBuf,
+    },
+
+    /// Find the most likely continuations of a phrase with a beam search
+    Beam {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        /// The phrase to continue
+        initial_phrase: String,
+
+        /// Number of candidate continuations to keep after each token
+        #[arg(short, long, default_value_t = 10)]
+        width: usize,
+
+        /// Number of tokens to generate, not counting the markers between
+        /// sentences
+        #[arg(short('s'), long, default_value_t = 20)]
+        output_size: u32,
+
+        /// Number of continuations to print
+        #[arg(short('n'), long, default_value_t = 3)]
+        count: usize,
+
+        #[command(flatten)]
+        smoothing: SmoothingArgs,
+
+        #[command(flatten)]
+        backoff: BackoffArgs,
+    },
+
+    /// Score how likely a text is according to a saved transition matrix
+    Score {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        /// The text file to score. Reads from stdin if not given.
+        input_file: Option<PathBuf>,
+
+        /// Score each line of the input separately. Lines too short to score are
+        /// skipped.
+        #[arg(long)]
+        per_line: bool,
+
+        #[command(flatten)]
+        smoothing: SmoothingArgs,
+    },
+
+    /// Rank saved transition matrices by how likely they are to have generated a
+    /// text
+    Classify {
+        /// Saved transition matrix files to compare
+        #[arg(required = true)]
+        models: Vec<PathBuf>,
+
+        /// The text file to classify. Reads from stdin if not given.
+        #[arg(short, long)]
+        input_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        smoothing: SmoothingArgs,
+    },
+
+    /// Print statistics about a saved transition matrix
+    Stats {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        /// Number of most frequent states to list
+        #[arg(short('n'), long, default_value_t = 10)]
+        top: usize,
+
+        /// Print the statistics as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Find the long run probability of each state of a saved transition matrix,
+    /// i.e. its stationary distribution
+    Stationary {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        /// Number of most likely states to list
+        #[arg(short('n'), long, default_value_t = 10)]
+        top: usize,
+
+        /// Stop once the total change in probability of an iteration is below this
+        #[arg(long, default_value_t = 1e-10)]
+        tolerance: f64,
+
+        /// Maximum number of iterations to run
+        #[arg(long, default_value_t = 10_000)]
+        max_iterations: usize,
+
+        /// Probability of jumping to a random state at each step, which makes the
+        /// distribution unique when the chain has several closed components
+        #[arg(long, default_value_t = 0.0, value_parser = parse_teleport)]
+        teleport: f64,
+    },
+
+    /// Export the transitions of a saved transition matrix as a graph
+    Export {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        #[arg(short, long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+
+        /// File to write the graph to. Writes to stdout if not given.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Leave out transitions which happened fewer times than this
+        #[arg(long, default_value_t = 1)]
+        min_count: Count,
+
+        /// Include at most this many states, keeping the most frequent or, with
+        /// `--from`, the closest ones
+        #[arg(long)]
+        max_nodes: Option<usize>,
+
+        /// Only include states reachable from the state ending this phrase
+        #[arg(long, value_name = "PHRASE")]
+        from: Option<String>,
+
+        /// Maximum number of steps from the `--from` state
+        #[arg(long, default_value_t = 2, requires = "from")]
+        depth: usize,
+    },
+
+    /// Remove rare transitions from a saved transition matrix, to make it smaller
+    Prune {
+        /// A saved transition matrix file
+        model: PathBuf,
+
+        #[command(flatten)]
+        prune: PruneArgs,
+
+        /// File to save the pruned transition matrix to
+        #[arg(long, default_value = "markov.bin")]
+        save: PathBuf,
+    },
+
+    /// Check a saved transition matrix for corruption
+    Verify {
+        /// A saved transition matrix file
+        model: PathBuf,
+    },
+}
+
+static DEFAULT_STATE_SIZE: u32 = 2;
+
+/// How many times to try generating each sentence with `--sentences` before
+/// giving up.
+static MAX_SENTENCE_ATTEMPTS: u32 = 100;
+
+type TokenId = u32;
+
+/// How many times a transition was seen. Counts are capped at the
+/// [`CountWidth`] of the generator they're in, but always take this many bits
+/// in memory.
+type Count = u64;
+
+/// Table of every distinct token seen while training. States refer to tokens by
+/// their position in this table.
+#[derive(Default, Serialize, Deserialize)]
+struct Vocabulary(IndexSet<String>);
+
+impl Vocabulary {
+    /// Get the id of `token`, adding it to the vocabulary if it's new.
+    pub fn intern(&mut self, token: &str) -> TokenId {
+        match self.0.get_index_of(token) {
+            Some(id) => id as TokenId,
+            None => self.0.insert_full(token.to_owned()).0 as TokenId,
+        }
+    }
+
+    pub fn get_id(&self, token: &str) -> Option<TokenId> {
+        self.0.get_index_of(token).map(|id| id as TokenId)
+    }
+
+    pub fn get_token(&self, id: TokenId) -> Option<&str> {
+        self.0.get_index(id as usize).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct State(Box<[TokenId]>);
+
+impl State {
+    pub fn new(tokens: Box<[TokenId]>) -> Self {
+        Self(tokens)
+    }
+
+    pub fn from_slice(tokens: &[TokenId], state_size: usize) -> Self {
+        // Copy the last `size` tokens
+        let index = tokens.len().saturating_sub(state_size);
+        let slice = &tokens[index..];
+        Self::new(slice.into())
+    }
+}
+
+impl Deref for State {
+    type Target = [TokenId];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+// Making a trait for this to benchmark performance for
+// a few implementations.
+trait StateIndex {
+    fn get_state(&self, index: usize) -> Option<&State>;
+    fn get_index(&self, state: &State) -> Option<usize>;
+    fn insert(&mut self, index: usize, state: State);
+    fn len(&self) -> usize;
+}
+
+impl StateIndex for Vec<State> {
+    fn get_state(&self, index: usize) -> Option<&State> {
+        self.get(index)
+    }
+
+    fn get_index(&self, state: &State) -> Option<usize> {
+        self.iter().position(|s| s == state)
+    }
+
+    fn insert(&mut self, index: usize, state: State) {
+        self.insert(index, state)
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Hashed index keeping states in insertion order, so lookups in both directions
+/// are O(1).
+impl StateIndex for IndexSet<State> {
+    fn get_state(&self, index: usize) -> Option<&State> {
+        self.get_index(index)
+    }
+
+    fn get_index(&self, state: &State) -> Option<usize> {
+        self.get_index_of(state)
+    }
+
+    fn insert(&mut self, index: usize, state: State) {
+        self.shift_insert(index, state);
+    }
+
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Transition counts accumulated while training. Inserting into a compressed
+/// matrix is O(nnz), so counts are kept in a hash map and compressed in one go
+/// once every transition has been seen.
+struct TransitionCounts {
+    counts: HashMap<(usize, usize), Count>,
+    /// Counts are capped at this rather than overflowing.
+    max: Count,
+    /// Transitions which would have gone past `max`.
+    saturated: HashSet<(usize, usize)>,
+}
+
+impl Default for TransitionCounts {
+    fn default() -> Self {
+        Self {
+            counts: HashMap::new(),
+            max: Count::MAX,
+            saturated: HashSet::new(),
+        }
+    }
+}
+
+impl TransitionCounts {
+    pub fn from_matrix(mat: &sprs::CsMat<Count>, max: Count) -> Self {
+        let mut counts = Self {
+            max,
+            ..Default::default()
+        };
+        for (&count, (from, to)) in mat.iter() {
+            counts.add(from, to, count);
+        }
+        counts
+    }
+
+    pub fn add(&mut self, from: usize, to: usize, count: Count) {
+        if count == 0 {
+            return;
+        }
+
+        let total = self.counts.entry((from, to)).or_default();
+        match total.checked_add(count).filter(|&sum| sum <= self.max) {
+            Some(sum) => *total = sum,
+            None => {
+                *total = self.max;
+                self.saturated.insert((from, to));
+            }
+        }
+    }
+
+    /// Compress the counts into a `n_states x n_states` matrix.
+    pub fn into_matrix(self, n_states: usize) -> sprs::CsMat<Count> {
+        let mut triplets = sprs::TriMat::with_capacity((n_states, n_states), self.counts.len());
+        for ((from, to), count) in self.counts {
+            triplets.add_triplet(from, to, count);
+        }
+        triplets.to_csr()
+    }
+}
+
+/// Transitions between the states of a single order, i.e. states which all have
+/// the same number of tokens.
+#[derive(Serialize, Deserialize)]
+struct Chain<S>
+where
+    S: StateIndex + Default,
+{
+    mat: sprs::CsMat<Count>,
+    states: S,
+}
+
+impl<S> Chain<S>
+where
+    S: StateIndex + Default,
+{
+    pub fn new() -> Self {
+        Self {
+            mat: sprs::CsMat::zero((0, 0)),
+            states: Default::default(),
+        }
+    }
+
+    /// Get the index of `state`, appending it to the state index if it's new.
+    fn index_or_insert(&mut self, state: State) -> usize {
+        match self.states.get_index(&state) {
+            Some(index) => index,
+            None => {
+                let index = self.states.len();
+                self.states.insert(index, state);
+                index
+            }
+        }
+    }
+
+    /// The counts of every state following `state`, if it is known.
+    pub fn successors(&self, state: &State) -> Option<sprs::CsVecView<'_, Count>> {
+        let row = self.states.get_index(state)?;
+        self.mat.outer_view(row)
+    }
+
+    /// Learn the transitions between the states of `order` tokens in `tokens`,
+    /// adding to the counts already in the matrix. Returns how many transitions
+    /// were seen more than `max_count` times.
+    pub fn train(&mut self, tokens: &[TokenId], order: usize, max_count: Count) -> usize {
+        let mut counts = TransitionCounts::from_matrix(&self.mat, max_count);
+        let mut last_state_index = None;
+        for window in tokens.windows(order) {
+            let row = self.index_or_insert(State::new(window.into()));
+
+            // Rows are the previous state, columns the state that followed it
+            if let Some(prev) = last_state_index {
+                counts.add(prev, row, 1);
+            }
+
+            last_state_index = Some(row);
+        }
+
+        let saturated = counts.saturated.len();
+        self.mat = counts.into_matrix(self.states.len());
+        saturated
+    }
+
+    /// Add the states and transitions of `other` to this chain, with its counts
+    /// scaled by `weight`, but never below 1 so that scaling down doesn't drop
+    /// transitions. `token_ids` maps the token ids of `other` to ours. Returns
+    /// how many transitions would have gone past `max_count`.
+    pub fn merge(
+        &mut self,
+        other: &Self,
+        token_ids: &[TokenId],
+        weight: f64,
+        max_count: Count,
+    ) -> usize {
+        let state_indicies: Vec<usize> = (0..other.states.len())
+            .map(|i| {
+                let state = other.states.get_state(i).unwrap();
+                self.index_or_insert(State::new(
+                    state.iter().map(|&id| token_ids[id as usize]).collect(),
+                ))
+            })
+            .collect();
+
+        let mut counts = TransitionCounts::from_matrix(&self.mat, max_count);
+        for (&count, (from, to)) in other.mat.iter() {
+            // Float to int casts saturate, counts too large for a float are capped
+            // by `add`
+            let scaled = ((count as f64 * weight).round() as Count).max(1);
+            counts.add(state_indicies[from], state_indicies[to], scaled);
+        }
+
+        let saturated = counts.saturated.len();
+        self.mat = counts.into_matrix(self.states.len());
+        saturated
+    }
+
+    /// Build the chain of states one token shorter than this one's, by summing
+    /// the transitions between the suffixes of our states, capped at `max_count`.
+    fn derive_lower_order(&self, max_count: Count) -> Self {
+        let mut lower = Self::new();
+        let state_indicies: Vec<usize> = (0..self.states.len())
+            .map(|i| {
+                let state = self.states.get_state(i).unwrap();
+                lower.index_or_insert(State::new(state[1..].into()))
+            })
+            .collect();
+
+        let mut counts = TransitionCounts {
+            max: max_count,
+            ..Default::default()
+        };
+        for (&count, (from, to)) in self.mat.iter() {
+            counts.add(state_indicies[from], state_indicies[to], count);
+        }
+
+        lower.mat = counts.into_matrix(lower.states.len());
+        lower
+    }
+}
+
+/// What to do when the current state has no known successors.
+#[derive(Clone, Copy)]
+enum Backoff {
+    /// Jump to a random state.
+    None,
+    /// Use the successors of the longest suffix of the state that has any.
+    Longest,
+    /// "Stupid backoff": successors of every suffix are considered, each order
+    /// shorter scaling their weights down by the given factor.
+    Stupid(f64),
+}
+
+type MarkovGenerator = MarkovGeneratorBase<IndexSet<State>>;
+
+// TODO: Consider a custom ser/de impelmentation to avoid writing the size for every state
+#[derive(Serialize, Deserialize)]
+struct MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    vocab: Vocabulary,
+    /// One chain per order, `chains[i]` has states of `i + 1` tokens. The last
+    /// chain is the full order, the others are used to back off from it.
+    chains: Vec<Chain<S>>,
+    state_size: u32,
+    /// Saved in the file header rather than with the rest of the generator.
+    #[serde(skip)]
+    metadata: Metadata,
+}
+
+/// How text is split into tokens, which has to be the same for everything a
+/// generator is trained on or asked about.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct TokenizerOptions {
+    /// Whether sentences are surrounded by sentence markers.
+    sentence_markers: bool,
+    /// Name of the registered tokenizer.
+    name: String,
+}
+
+fn default_tokenizer() -> String {
+    tokenizer::registered().swap_remove(0).name
+}
+
+impl Default for TokenizerOptions {
+    fn default() -> Self {
+        Self {
+            sentence_markers: true,
+            name: default_tokenizer(),
+        }
+    }
+}
+
+impl TokenizerOptions {
+    /// The tokenizer and detokenizer registered under `name`.
+    fn registered(&self) -> io::Result<tokenizer::Registered> {
+        tokenizer::find(&self.name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the matrix was trained with the {} tokenizer, which isn't available",
+                    self.name
+                ),
+            )
+        })
+    }
+}
+
+/// What a generator was trained on.
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Metadata {
+    tokenizer: TokenizerOptions,
+    /// Number of tokens trained on, including sentence markers.
+    tokens: u64,
+    /// When the generator was first trained, in seconds since the Unix epoch,
+    /// or 0 if it was saved before this was recorded.
+    created: u64,
+    /// Names of the files trained on.
+    sources: Vec<String>,
+    count_width: CountWidth,
+    /// Used when generating from or scoring with the generator, unless told
+    /// otherwise.
+    smoothing: Smoothing,
+}
+
+impl Metadata {
+    /// Best guess at the metadata of a generator saved without any, going by
+    /// its vocabulary.
+    fn from_vocabulary(vocab: &Vocabulary) -> Self {
+        Self {
+            tokenizer: TokenizerOptions {
+                sentence_markers: vocab.get_id(SENTENCE_START).is_some(),
+                name: default_tokenizer(),
+            },
+            tokens: 0,
+            created: 0,
+            sources: Vec::new(),
+            count_width: CountWidth::U16,
+            smoothing: Smoothing::None,
+        }
+    }
+}
+
+/// The current time in seconds since the Unix epoch, for [`Metadata::created`].
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    pub fn new(state_size: u32) -> Self {
+        Self {
+            vocab: Default::default(),
+            chains: (0..state_size).map(|_| Chain::new()).collect(),
+            state_size,
+            metadata: Default::default(),
+        }
+    }
+
+    /// The chain of full sized states.
+    pub fn chain(&self) -> &Chain<S> {
+        self.chains.last().unwrap()
+    }
+
+    /// Learn the transitions in the text read from `reader`, split into tokens by
+    /// `tokenizer`, adding to the counts already in the matrix. New states are
+    /// appended to the state index. Returns how many transitions were seen too
+    /// often for the count width.
+    pub fn train(
+        &mut self,
+        reader: &mut dyn BufRead,
+        tokenizer: &dyn Tokenizer,
+    ) -> io::Result<usize> {
+        let tokens = tokenizer.tokenize(reader, self.metadata.tokenizer.sentence_markers)?;
+        let tokens: Vec<TokenId> = tokens.iter().map(|t| self.vocab.intern(t)).collect();
+        let max_count = self.metadata.count_width.max_count();
+        let mut saturated = 0;
+        for (i, chain) in self.chains.iter_mut().enumerate() {
+            saturated += chain.train(&tokens, i + 1, max_count);
+        }
+        self.metadata.tokens += tokens.len() as u64;
+        Ok(saturated)
+    }
+
+    /// Add the states and transitions of `other` to this generator, with its
+    /// counts scaled by `weight`. States are matched by their tokens, so the two
+    /// generators don't need to share a vocabulary. Counts are widened to fit
+    /// the wider of the two generators. Returns how many transitions were seen
+    /// too often for the count width.
+    pub fn merge(&mut self, other: &Self, weight: f64) -> usize {
+        assert_eq!(
+            self.state_size, other.state_size,
+            "Can't merge generators with different state sizes"
+        );
+
+        self.metadata.count_width = self.metadata.count_width.max(other.metadata.count_width);
+        let max_count = self.metadata.count_width.max_count();
+        let token_ids: Vec<TokenId> = other.vocab.iter().map(|t| self.vocab.intern(t)).collect();
+        let mut saturated = 0;
+        for (chain, other_chain) in self.chains.iter_mut().zip(&other.chains) {
+            saturated += chain.merge(other_chain, &token_ids, weight, max_count);
+        }
+
+        self.metadata.tokens += other.metadata.tokens;
+        for source in &other.metadata.sources {
+            if !self.metadata.sources.contains(source) {
+                self.metadata.sources.push(source.clone());
+            }
+        }
+        saturated
+    }
+
+    /// The text of each token in `state`.
+    pub fn state_tokens<'a>(&'a self, state: &'a State) -> impl Iterator<Item = &'a str> + 'a {
+        state.iter().map(|&id| {
+            self.vocab
+                .get_token(id)
+                .expect("State refers to unknown token")
+        })
+    }
+}
+
+/// What's needed to generate text from a trained generator. Implemented both by
+/// generators loaded into memory and by ones queried in place from a file.
+trait Model {
+    fn state_size(&self) -> u32;
+
+    /// Number of full sized states.
+    fn state_count(&self) -> usize;
+
+    fn token_id(&self, token: &str) -> Option<TokenId>;
+
+    fn token(&self, id: TokenId) -> Option<&str>;
+
+    /// Number of tokens in the vocabulary, which have ids from 0 up to this.
+    fn vocab_size(&self) -> usize;
+
+    /// The smoothing recorded with the generator.
+    fn smoothing(&self) -> Smoothing;
+
+    /// How the text the generator was trained on was split into tokens.
+    fn tokenizer(&self) -> &TokenizerOptions;
+
+    /// The index of `state` in the chain of states the same length as it.
+    fn find_state(&self, state: &State) -> Option<usize>;
+
+    /// How many distinct states lead to each state of `order` tokens, by index.
+    fn in_degrees(&self, order: usize) -> Vec<u32>;
+
+    /// The last token of each state following `state` and how many times it did,
+    /// in the chain of states the same length as `state`.
+    fn transitions(&self, state: &State) -> Vec<(TokenId, Count)>;
+
+    /// Pick a random full sized state.
+    fn random_state<R: Rng + ?Sized>(&self, rng: &mut R) -> State;
+
+    /// Pick a random state which starts a sentence, weighted by how often it was
+    /// followed by something while training. Returns `None` if the training text
+    /// had no sentence markers.
+    fn random_sentence_start<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<State>;
+
+    /// Build the state ending with the last tokens of `tokens`, stopping at the
+    /// first token which isn't in the vocabulary.
+    fn state_from_tokens(&self, tokens: &[String]) -> Option<State> {
+        let mut ids: Vec<TokenId> = tokens
+            .iter()
+            .rev()
+            .take(self.state_size() as usize)
+            .map_while(|t| self.token_id(t))
+            .collect();
+        ids.reverse();
+
+        (!ids.is_empty()).then(|| State::new(ids.into()))
+    }
+
+    /// Generate a single sentence, including its sentence markers. Returns `None`
+    /// if the training text had no sentence markers, or if the sentence didn't
+    /// end within `max_tokens` tokens, not counting the markers.
+    fn generate_sentence<R: Rng + ?Sized>(
+        &self,
+        max_tokens: u32,
+        backoff: Backoff,
+        smoother: &Smoother,
+        sampling: &SamplingConfig,
+        rng: &mut R,
+    ) -> Option<Vec<TokenId>> {
+        let start = self.token_id(SENTENCE_START)?;
+        let end = self.token_id(SENTENCE_END)?;
+        let mut state = self.random_sentence_start(rng)?;
+
+        let mut sentence = Vec::new();
+        let mut length = 0;
+        for &token in state.iter() {
+            sentence.push(token);
+            if token == end {
+                return Some(sentence);
+            }
+            if token != start {
+                length += 1;
+            }
+        }
+
+        while length <= max_tokens {
+            state = self.predict(&state, backoff, smoother, sampling, rng);
+            let token = *state.last().unwrap();
+            sentence.push(token);
+            if token == end {
+                return Some(sentence);
+            }
+            if token != start {
+                length += 1;
+            }
+        }
+
+        None
+    }
+
+    /// Weights of each token which could follow `state`, according to `backoff`,
+    /// or the probabilities given by `smoother` if it smooths them. `state` may be
+    /// shorter than a full state.
+    fn successors(
+        &self,
+        state: &State,
+        backoff: Backoff,
+        smoother: &Smoother,
+    ) -> Vec<(TokenId, f64)> {
+        if smoother.smoothing() != Smoothing::None {
+            return smoother.distribution(self, state);
+        }
+
+        let mut successors: Vec<(TokenId, f64)> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut scale = 1.0;
+        for order in (1..=state.len().min(self.state_size() as usize)).rev() {
+            let is_full_order = order == self.state_size() as usize;
+            if matches!(backoff, Backoff::None) && !is_full_order {
+                break;
+            }
+
+            let transitions = self.transitions(&State::from_slice(state, order));
+            if transitions.is_empty() {
+                if let Backoff::Stupid(factor) = backoff {
+                    scale *= factor;
+                }
+                continue;
+            }
+
+            let total: f64 = transitions.iter().map(|&(_, count)| count as f64).sum();
+            for (token, count) in transitions {
+                if seen.insert(token) {
+                    successors.push((token, scale * count as f64 / total));
+                }
+            }
+
+            match backoff {
+                Backoff::Stupid(factor) => scale *= factor,
+                Backoff::None | Backoff::Longest => break,
+            }
+        }
+
+        successors
+    }
+
+    /// Pick a state following `current_state`. Only the last token of the returned
+    /// state is new, the rest overlaps with `current_state`.
+    fn predict<R: Rng + ?Sized>(
+        &self,
+        current_state: &State,
+        backoff: Backoff,
+        smoother: &Smoother,
+        sampling: &SamplingConfig,
+        rng: &mut R,
+    ) -> State {
+        let successors = self.successors(current_state, backoff, smoother);
+
+        // If no next tokens are available, pick a state at random
+        let Some(selected) = sampling.sample(&successors, rng) else {
+            return self.random_state(rng);
+        };
+
+        let mut next_state = current_state.to_vec();
+        next_state.push(selected);
+        State::from_slice(&next_state, self.state_size() as usize)
+    }
+}
+
+impl<S> Model for MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    fn state_size(&self) -> u32 {
+        self.state_size
+    }
+
+    fn state_count(&self) -> usize {
+        self.chain().states.len()
+    }
+
+    fn token_id(&self, token: &str) -> Option<TokenId> {
+        self.vocab.get_id(token)
+    }
+
+    fn token(&self, id: TokenId) -> Option<&str> {
+        self.vocab.get_token(id)
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.vocab.len()
+    }
+
+    fn smoothing(&self) -> Smoothing {
+        self.metadata.smoothing
+    }
+
+    fn tokenizer(&self) -> &TokenizerOptions {
+        &self.metadata.tokenizer
+    }
+
+    fn find_state(&self, state: &State) -> Option<usize> {
+        let chain = self.chains.get(state.len().checked_sub(1)?)?;
+        chain.states.get_index(state)
+    }
+
+    fn in_degrees(&self, order: usize) -> Vec<u32> {
+        let chain = &self.chains[order - 1];
+        let mut in_degrees = vec![0; chain.states.len()];
+        for &to in chain.mat.indices() {
+            in_degrees[to] += 1;
+        }
+        in_degrees
+    }
+
+    fn transitions(&self, state: &State) -> Vec<(TokenId, Count)> {
+        let Some(chain) = state.len().checked_sub(1).and_then(|i| self.chains.get(i)) else {
+            return Vec::new();
+        };
+        let Some(row) = chain.successors(state) else {
+            return Vec::new();
+        };
+
+        row.iter()
+            .map(|(col, &count)| (*chain.states.get_state(col).unwrap().last().unwrap(), count))
+            .collect()
+    }
+
+    fn random_state<R: Rng + ?Sized>(&self, rng: &mut R) -> State {
+        let chain = self.chain();
+        let index = rng.random_range(0..chain.states.len());
+        chain.states.get_state(index).unwrap().clone()
+    }
+
+    fn random_sentence_start<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<State> {
+        let start = self.vocab.get_id(SENTENCE_START)?;
+        let chain = self.chain();
+        let (indicies, weights): (Vec<usize>, Vec<u64>) = (0..chain.states.len())
+            .filter(|&i| chain.states.get_state(i).unwrap().first() == Some(&start))
+            .map(|i| {
+                let row = chain.mat.outer_view(i).unwrap();
+                let count: u64 = row.data().iter().sum();
+                (i, count.max(1))
+            })
+            .unzip();
+
+        let dist = WeightedIndex::new(weights).ok()?;
+        chain.states.get_state(indicies[dist.sample(rng)]).cloned()
+    }
+}
+
+/// Marks the start of a sentence in the training text. The tokenizer never
+/// produces a token mixing letters followed by punctuation, so this can't appear
+/// in the text itself.
+pub static SENTENCE_START: &str = "<s>";
+/// Marks the end of a sentence in the training text.
+pub static SENTENCE_END: &str = "</s>";
+
+/// Load a saved transition matrix from `path`, exiting with an error if it isn't one.
+fn load_model(path: &Path) -> io::Result<MarkovGenerator> {
+    let mut reader = BufReader::new(File::open(path)?);
+    match read_model(&mut reader, Some(path))? {
+        Some(markov) => Ok(markov),
+        None => Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!("{} is not a saved transition matrix", path.display()),
+            )
+            .exit(),
+    }
+}
+
+fn merge_models(paths: &[PathBuf], weights: &[f64], output_path: &Path) -> io::Result<()> {
+    if !weights.is_empty() && weights.len() != paths.len() {
+        Args::command()
+            .error(
+                ErrorKind::WrongNumberOfValues,
+                format!("got {} weights for {} matrices", weights.len(), paths.len()),
+            )
+            .exit();
+    }
+
+    let mut merged: Option<MarkovGenerator> = None;
+    let mut saturated = 0;
+    for (i, path) in paths.iter().enumerate() {
+        let markov = load_model(path)?;
+        let weight = weights.get(i).copied().unwrap_or(1.0);
+        let merged = merged.get_or_insert_with(|| {
+            let mut merged = MarkovGenerator::new(markov.state_size);
+            merged.metadata.created = unix_time();
+            merged.metadata.tokenizer = markov.metadata.tokenizer.clone();
+            merged.metadata.smoothing = markov.metadata.smoothing;
+            merged.metadata.count_width = markov.metadata.count_width;
+            merged
+        });
+        if markov.state_size != merged.state_size {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "{} has a state size of {}, but the other matrices have a state size of {}",
+                        path.display(),
+                        markov.state_size,
+                        merged.state_size
+                    ),
+                )
+                .exit();
+        }
+        if markov.metadata.tokenizer != merged.metadata.tokenizer {
+            Args::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!(
+                        "{} was trained with different tokenizer options than the other matrices",
+                        path.display()
+                    ),
+                )
+                .exit();
+        }
+        saturated += merged.merge(&markov, weight);
+    }
+
+    let merged = merged.unwrap();
+    warn_saturated(saturated, merged.metadata.count_width);
+    write_model(output_path, &merged)
+}
+
+fn beam_search(
+    model_path: &Path,
+    initial_phrase: &str,
+    width: usize,
+    output_size: u32,
+    count: usize,
+    smoothing: Option<Smoothing>,
+    backoff: Backoff,
+) -> io::Result<()> {
+    let markov = load_model(model_path)?;
+    let smoother = Smoother::new(&markov, smoothing.unwrap_or(markov.metadata.smoothing));
+    let tokenizer = markov.metadata.tokenizer.registered()?;
+    let initial_tokens = tokenizer.tokenizer.split(initial_phrase)?;
+    let Some(initial_state) = markov.state_from_tokens(&initial_tokens) else {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "the phrase must end with a token the matrix was trained on",
+            )
+            .exit();
+    };
+
+    let continuations = markov.beam_search(
+        &initial_state,
+        width.max(count),
+        output_size,
+        backoff,
+        &smoother,
+    );
+    for continuation in continuations.iter().take(count) {
+        let mut output = initial_tokens.clone();
+        output.extend(
+            continuation
+                .tokens
+                .iter()
+                .map(|&id| markov.vocab.get_token(id).unwrap().to_owned()),
+        );
+        println!(
+            "{:.3}\t{}",
+            continuation.log_prob,
+            tokenizer.detokenizer.detokenize(&output)
+        );
+    }
+
+    Ok(())
+}
+
+/// The smoother to score text with, `smoothing` if given and otherwise the one
+/// saved with `markov`. Without any smoothing, a single unseen transition would
+/// make the whole text impossible, so unsmoothed matrices are smoothed additively.
+fn scoring_smoother(markov: &MarkovGenerator, smoothing: Option<Smoothing>) -> Smoother {
+    let smoothing = smoothing.unwrap_or(match markov.metadata.smoothing {
+        Smoothing::None => Smoothing::Additive(1.0),
+        smoothing => smoothing,
+    });
+    Smoother::new(markov, smoothing)
+}
+
+fn score_text(
+    model_path: &Path,
+    input_path: Option<&Path>,
+    per_line: bool,
+    smoothing: Option<Smoothing>,
+) -> io::Result<()> {
+    let markov = load_model(model_path)?;
+    let smoother = scoring_smoother(&markov, smoothing);
+    let tokenizer = markov.metadata.tokenizer.registered()?.tokenizer;
+    let mut reader: Box<dyn BufRead> = match input_path {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    if per_line {
+        for line in reader.lines() {
+            let line = line?;
+            let score = markov.score(&mut line.as_bytes(), &*tokenizer, &smoother)?;
+            if score.tokens > 0 {
+                println!("{:.3}\t{:.3}\t{}", score.perplexity(), score.log_prob, line);
+            }
+        }
+        return Ok(());
+    }
+
+    let score = markov.score(&mut reader, &*tokenizer, &smoother)?;
+    if score.tokens == 0 {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                format!(
+                    "the text must be more than {} tokens long to be scored",
+                    markov.state_size
+                ),
+            )
+            .exit();
+    }
+
+    println!("Tokens scored: {}", score.tokens);
+    println!("Log probability: {:.3}", score.log_prob);
+    println!("Perplexity: {:.3}", score.perplexity());
+    println!("Unseen transitions: {}", score.unseen);
+    Ok(())
+}
+
+fn classify_text(
+    model_paths: &[PathBuf],
+    input_path: Option<&Path>,
+    smoothing: Option<Smoothing>,
+) -> io::Result<()> {
+    let mut text = String::new();
+    match input_path {
+        Some(path) => File::open(path)?.read_to_string(&mut text)?,
+        None => io::stdin().read_to_string(&mut text)?,
+    };
+
+    // Each matrix tokenizes the text itself, in case they were trained differently
+    let mut scores = Vec::new();
+    for path in model_paths {
+        let markov = load_model(path)?;
+        let smoother = scoring_smoother(&markov, smoothing);
+        let tokenizer = markov.metadata.tokenizer.registered()?.tokenizer;
+        let score = markov.score(&mut text.as_bytes(), &*tokenizer, &smoother)?;
+        if score.tokens == 0 {
+            Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    format!(
+                        "the text must be more than {} tokens long to be scored by {}",
+                        markov.state_size,
+                        path.display()
+                    ),
+                )
+                .exit();
+        }
+        scores.push(score);
+    }
+
+    let probabilities = score::normalize(&scores);
+    let mut ranked: Vec<_> = model_paths.iter().zip(&scores).zip(probabilities).collect();
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    for ((path, score), probability) in ranked {
+        println!(
+            "{:.4}\t{:.3}\t{}",
+            probability,
+            score.perplexity(),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn print_stats(model_path: &Path, top: usize, json: bool) -> io::Result<()> {
+    let stats = load_model(model_path)?.stats(top);
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        println!("{stats}");
+    }
+    Ok(())
+}
+
+/// Prune `markov` as asked for by `prune`, returning a report of what was
+/// removed. Exits with an error if nothing would be left to generate from.
+fn prune(markov: &mut MarkovGenerator, prune: &PruneArgs) -> Option<String> {
+    let options = prune.options()?;
+    let pruned = markov.prune(&options);
+    if markov.chain().states.is_empty() {
+        Args::command()
+            .error(ErrorKind::InvalidValue, "pruning would remove every state")
+            .exit();
+    }
+    Some(pruned.to_string())
+}
+
+fn prune_model(model_path: &Path, prune_args: &PruneArgs, output_path: &Path) -> io::Result<()> {
+    let mut markov = load_model(model_path)?;
+    match prune(&mut markov, prune_args) {
+        Some(report) => println!("{report}"),
+        None => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "nothing to prune, give --prune-min-count above 1 or --prune-max-successors",
+            )
+            .exit(),
+    }
+    write_model(output_path, &markov)
+}
+
+fn verify_model(model_path: &Path) -> io::Result<()> {
+    let problems = load_model(model_path)?.verify();
+    if problems.is_empty() {
+        println!("{}: OK", model_path.display());
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}: {problem}", model_path.display());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "found {} problems in {}",
+            problems.len(),
+            model_path.display()
+        ),
+    ))
+}
+
+fn print_stationary(model_path: &Path, top: usize, options: StationaryOptions) -> io::Result<()> {
+    let markov = load_model(model_path)?;
+    let stationary = markov.stationary_distribution(options);
+
+    if stationary.converged {
+        println!("Converged after {} iterations", stationary.iterations);
+    } else {
+        println!(
+            "Did not converge after {} iterations, try a larger --max-iterations",
+            stationary.iterations
+        );
+    }
+    println!(
+        "Dead ends: {} (their probability is spread over every state)",
+        stationary.dead_ends
+    );
+
+    let components = &stationary.closed_components;
+    println!("Closed components: {}", components.len());
+    if components.len() > 1 && options.teleport == 0.0 {
+        println!(
+            "The chain is not ergodic, so the distribution depends on starting uniformly over \
+             every state. Use --teleport to make it unique."
+        );
+    }
+    for component in components {
+        let probability: f64 = component.iter().map(|&i| stationary.distribution[i]).sum();
+        println!(
+            "  {} states with probability {:.6}",
+            component.len(),
+            probability
+        );
+    }
+
+    let mut ranked: Vec<(usize, f64)> = stationary.distribution.into_iter().enumerate().collect();
+    ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    println!("Most likely states:");
+    for (i, probability) in ranked.into_iter().take(top) {
+        let state = markov.chain().states.get_state(i).unwrap();
+        let tokens: Vec<&str> = markov.state_tokens(state).collect();
+        println!("  {:.6}: {}", probability, tokens.join(" "));
+    }
+
+    Ok(())
+}
+
+fn export_graph(
+    model_path: &Path,
+    format: GraphFormat,
+    output_path: Option<&Path>,
+    min_count: Count,
+    max_nodes: Option<usize>,
+    from: Option<(&str, usize)>,
+) -> io::Result<()> {
+    let markov = load_model(model_path)?;
+    let from = match from {
+        Some((phrase, depth)) => {
+            let tokens = markov
+                .metadata
+                .tokenizer
+                .registered()?
+                .tokenizer
+                .split(phrase)?;
+            match markov.state_from_tokens(&tokens) {
+                Some(state) if state.len() == markov.state_size as usize => Some((state, depth)),
+                _ => Args::command()
+                    .error(
+                        ErrorKind::InvalidValue,
+                        format!(
+                            "--from must end with {} tokens the matrix was trained on",
+                            markov.state_size
+                        ),
+                    )
+                    .exit(),
+            }
+        }
+        None => None,
+    };
+
+    let options = GraphOptions {
+        min_count,
+        max_nodes,
+        from,
+    };
+    let Some(graph) = markov.transition_graph(&options) else {
+        Args::command()
+            .error(
+                ErrorKind::InvalidValue,
+                "the --from phrase never appeared in the training text",
+            )
+            .exit();
+    };
+
+    let mut writer: Box<dyn Write> = match output_path {
+        Some(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    match format {
+        GraphFormat::Dot => markov.write_dot(&graph, &mut writer)?,
+        GraphFormat::Graphml => markov.write_graphml(&graph, &mut writer)?,
+    }
+    writer.flush()
+}
+
+/// Warn that the counts of `saturated` transitions were capped at the largest
+/// count `count_width` can hold.
+fn warn_saturated(saturated: usize, count_width: CountWidth) {
+    if saturated > 0 {
+        eprintln!(
+            "warning: {saturated} transitions were seen more than {} times, the most a {}-bit \
+             count can hold, so their counts were capped. Use a wider --count-width to keep them",
+            count_width.max_count(),
+            count_width.bits()
+        );
+    }
+}
+
+/// Fail if there's nothing to generate from, which happens when the training text
+/// had fewer tokens than the state size.
+fn check_not_empty<M: Model>(markov: &M) -> io::Result<()> {
+    if markov.state_count() == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the matrix has no states, its training text had fewer than {} tokens",
+                markov.state_size()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Exit with an error if `args` asks for a different state size or tokenizer
+/// than the loaded matrix has.
+fn check_loaded<M: Model>(args: &Args, markov: &M) {
+    let state_size = markov.state_size();
+    if args.state_size.is_some_and(|size| size != state_size) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "the loaded matrix has a state size of {state_size}, which can't be changed"
+                ),
+            )
+            .exit();
+    }
+
+    let name = &markov.tokenizer().name;
+    if args
+        .tokenizer
+        .as_ref()
+        .is_some_and(|tokenizer| tokenizer != name)
+    {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!(
+                    "the loaded matrix was trained with the {name} tokenizer, which can't be \
+                     changed"
+                ),
+            )
+            .exit();
+    }
+}
+
+/// Generate text from `markov` as asked for by `args`, and print it.
+fn generate<M: Model>(
+    markov: &M,
+    args: &Args,
+    tokenizer: &dyn Tokenizer,
+    detokenizer: &dyn Detokenizer,
+) -> io::Result<()> {
+    let backoff = args.backoff.backoff();
+
+    let sampling = SamplingConfig {
+        temperature: args.temperature,
+        top_k: args.top_k.map(|k| k as usize),
+        top_p: args.top_p,
+    };
+
+    check_not_empty(markov)?;
+
+    let seed = args.seed.unwrap_or_else(rand::random);
+    if args.print_seed {
+        eprintln!("Seed: {seed}");
+    }
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let smoother = Smoother::new(
+        markov,
+        args.smoothing.smoothing.unwrap_or(markov.smoothing()),
+    );
+    // Mapped files are only checked as they are read, so a token may be missing
+    let text = |id: TokenId| {
+        markov
+            .token(id)
+            .map(str::to_owned)
+            .ok_or_else(|| invalid_data(format!("Invalid matrix file: token {id} is malformed")))
+    };
+
+    if let Some(sentences) = args.sentences {
+        if markov.token_id(SENTENCE_END).is_none() {
+            Args::command()
+                .error(
+                    ErrorKind::InvalidValue,
+                    "the matrix was trained without sentence markers",
+                )
+                .exit();
+        }
+
+        let mut output = Vec::new();
+        for _ in 0..sentences {
+            let sentence = (0..MAX_SENTENCE_ATTEMPTS)
+                .find_map(|_| {
+                    markov.generate_sentence(
+                        args.output_size,
+                        backoff,
+                        &smoother,
+                        &sampling,
+                        &mut rng,
+                    )
+                })
+                .unwrap_or_else(|| {
+                    Args::command()
+                        .error(
+                            ErrorKind::InvalidValue,
+                            format!(
+                                "couldn't generate a sentence of at most {} tokens",
+                                args.output_size
+                            ),
+                        )
+                        .exit()
+                });
+            for id in sentence {
+                output.push(text(id)?);
+            }
+        }
+
+        println!("{}", detokenizer.detokenize(&output));
+        return Ok(());
+    }
+
+    let mut output = Vec::new();
+    let mut prev_state = if let Some(s) = &args.initial_phrase {
+        let initial_tokens = tokenizer.split(s)?;
+        // If the phrase contains unknown tokens, continue from a random state instead
+        let s = markov
+            .state_from_tokens(&initial_tokens)
+            .unwrap_or_else(|| markov.random_state(&mut rng));
+        output.extend(initial_tokens);
+        s
+    } else {
+        let s = match markov.random_sentence_start(&mut rng) {
+            Some(s) => s,
+            None => markov.random_state(&mut rng),
+        };
+        for &id in s.iter() {
+            output.push(text(id)?);
+        }
+        s
+    };
+
+    // Each prediction overlaps the previous state, so only its last token is new.
+    // Sentence markers aren't printed, so they don't count towards the output size.
+    let mut generated = 0;
+    while generated < args.output_size {
+        prev_state = markov.predict(&prev_state, backoff, &smoother, &sampling, &mut rng);
+        let Some(&id) = prev_state.last() else {
+            continue;
+        };
+        let new_token = text(id)?;
+        if new_token != SENTENCE_START && new_token != SENTENCE_END {
+            generated += 1;
+        }
+        output.push(new_token);
+    }
+
+    println!("{}", detokenizer.detokenize(&output));
+    Ok(())
+}
+
+/// Run the command line program with the arguments the process was started
+/// with. Tokenizers registered before this can be selected with `--tokenizer`.
+pub fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> io::Result<()> {
+    if let Some(command) = args.command {
+        return match command {
+            Command::Merge {
+                models,
+                weights,
+                save,
+            } => merge_models(&models, &weights, &save),
+            Command::Beam {
+                model,
+                initial_phrase,
+                width,
+                output_size,
+                count,
+                smoothing,
+                backoff,
+            } => beam_search(
+                &model,
+                &initial_phrase,
+                width,
+                output_size,
+                count,
+                smoothing.smoothing,
+                backoff.backoff(),
+            ),
+            Command::Score {
+                model,
+                input_file,
+                per_line,
+                smoothing,
+            } => score_text(&model, input_file.as_deref(), per_line, smoothing.smoothing),
+            Command::Classify {
+                models,
+                input_file,
+                smoothing,
+            } => classify_text(&models, input_file.as_deref(), smoothing.smoothing),
+            Command::Stats { model, top, json } => print_stats(&model, top, json),
+            Command::Stationary {
+                model,
+                top,
+                tolerance,
+                max_iterations,
+                teleport,
+            } => {
+                let options = StationaryOptions {
+                    tolerance,
+                    max_iterations,
+                    teleport,
+                };
+                print_stationary(&model, top, options)
+            }
+            Command::Export {
+                model,
+                format,
+                output,
+                min_count,
+                max_nodes,
+                from,
+                depth,
+            } => export_graph(
+                &model,
+                format,
+                output.as_deref(),
+                min_count,
+                max_nodes,
+                from.as_deref().map(|phrase| (phrase, depth)),
+            ),
+            Command::Prune { model, prune, save } => prune_model(&model, &prune, &save),
+            Command::Verify { model } => verify_model(&model),
+        };
+    }
+
+    // Generating doesn't need the whole matrix in memory, so query mapped files in
+    // place. Anything which changes the matrix first needs it loaded.
+    let in_place = args.train.is_empty()
+        && args.save.is_none()
+        && args.prune.options().is_none()
+        && args.count_width.is_none();
+    if let Some(path) = args.input_file.as_deref().filter(|_| in_place) {
+        if let Some(mapped) = MappedGenerator::open(path)? {
+            check_loaded(&args, &mapped);
+            let registered = mapped.tokenizer().registered()?;
+            return generate(
+                &mapped,
+                &args,
+                &*registered.tokenizer,
+                &*registered.detokenizer,
+            );
+        }
+    }
+
+    let source = args
+        .input_file
+        .as_ref()
+        .map_or_else(|| "-".to_owned(), |path| path.display().to_string());
+    let mut reader: Box<dyn BufRead> = if let Some(path) = &args.input_file {
+        Box::new(BufReader::new(File::open(path)?))
+    } else {
+        Box::new(BufReader::new(io::stdin()))
+    };
+
+    let mut saturated = 0;
+    let mut markov = match read_model(&mut reader, args.input_file.as_deref())? {
+        Some(mut markov) => {
+            check_loaded(&args, &markov);
+            if let Some(count_width) = args.count_width {
+                if count_width < markov.metadata.count_width {
+                    Args::command()
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            format!(
+                                "the loaded matrix has {}-bit counts, which can't be narrowed",
+                                markov.metadata.count_width.bits()
+                            ),
+                        )
+                        .exit();
+                }
+                markov.metadata.count_width = count_width;
+            }
+            markov
+        }
+        None => {
+            let mut markov = MarkovGenerator::new(args.state_size.unwrap_or(DEFAULT_STATE_SIZE));
+            markov.metadata.created = unix_time();
+            markov.metadata.count_width = args.count_width.unwrap_or_default();
+            if let Some(tokenizer) = &args.tokenizer {
+                markov.metadata.tokenizer.name = tokenizer.clone();
+            }
+            let tokenizer = markov.metadata.tokenizer.registered()?.tokenizer;
+            saturated += markov.train(&mut reader, &*tokenizer)?;
+            markov.metadata.sources.push(source);
+            markov
+        }
+    };
+
+    // Train with the loaded matrix's tokenizer options, so its states stay consistent
+    let registered = markov.metadata.tokenizer.registered()?;
+    for path in &args.train {
+        let mut reader = BufReader::new(File::open(path)?);
+        saturated += markov.train(&mut reader, &*registered.tokenizer)?;
+        markov.metadata.sources.push(path.display().to_string());
+    }
+    warn_saturated(saturated, markov.metadata.count_width);
+    if let Some(smoothing) = args.smoothing.smoothing {
+        markov.metadata.smoothing = smoothing;
+    }
+
+    if let Some(report) = prune(&mut markov, &args.prune) {
+        eprintln!("{report}");
+    }
+    check_not_empty(&markov)?;
+
+    if let Some(output_path) = &args.save {
+        return write_model(output_path, &markov);
+    }
+
+    generate(
+        &markov,
+        &args,
+        &*registered.tokenizer,
+        &*registered.detokenizer,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    pub const TEXT: &str = "To be, or not to be, that is the question: whether 'tis nobler in the \
+        mind to suffer the slings and arrows of outrageous fortune, or to take arms against a \
+        sea of troubles and by opposing end them. To die, to sleep, no more; and by a sleep to \
+        say we end the heart-ache and the thousand natural shocks that flesh is heir to! 'Tis \
+        a consummation devoutly to be wish'd. To die, to sleep; to sleep, perchance to dream.";
+
+    /// A generator trained on a few sentences, with `state_size` tokens per state.
+    pub fn trained(state_size: u32) -> MarkovGenerator {
+        let mut markov = MarkovGenerator::new(state_size);
+        markov
+            .train(&mut TEXT.as_bytes(), &tokenizer::Words)
+            .unwrap();
+        markov
+    }
+
+    /// Every transition of the full order chain, by the tokens of its states.
+    pub fn transitions(markov: &MarkovGenerator) -> Vec<(Vec<String>, Vec<String>, Count)> {
+        let chain = markov.chain();
+        let tokens = |i| {
+            let state = chain.states.get_state(i).unwrap();
+            markov.state_tokens(state).map(str::to_owned).collect()
+        };
+        let mut transitions: Vec<_> = chain
+            .mat
+            .iter()
+            .map(|(&count, (from, to))| (tokens(from), tokens(to), count))
+            .collect();
+        transitions.sort();
+        transitions
+    }
+
+    /// A path in the temporary directory, unique to this test run and `name`.
+    pub fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("markov-test-{}-{name}", std::process::id()))
+    }
+
+    /// Generate `count` tokens from `markov`, starting from a sentence start.
+    pub fn generate_tokens<M: Model>(
+        markov: &M,
+        smoothing: Smoothing,
+        seed: u64,
+        count: usize,
+    ) -> Vec<String> {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let smoother = Smoother::new(markov, smoothing);
+        let sampling = SamplingConfig::default();
+        let mut state = markov.random_sentence_start(&mut rng).unwrap();
+        (0..count)
+            .map(|_| {
+                state = markov.predict(&state, Backoff::Longest, &smoother, &sampling, &mut rng);
+                markov.token(*state.last().unwrap()).unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_generates_the_same_tokens() {
+        let markov = trained(2);
+        for smoothing in [Smoothing::None, Smoothing::KneserNey(0.75)] {
+            let first = generate_tokens(&markov, smoothing, 7, 100);
+            assert_eq!(first, generate_tokens(&markov, smoothing, 7, 100));
+            assert_ne!(first, generate_tokens(&markov, smoothing, 8, 100));
+        }
+    }
+
+    #[test]
+    fn sentences_end_within_max_tokens() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        let sampling = SamplingConfig::default();
+        let start = markov.token_id(SENTENCE_START).unwrap();
+        let end = markov.token_id(SENTENCE_END).unwrap();
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+
+        let mut checked = 0;
+        for _ in 0..20 {
+            let Some(sentence) =
+                markov.generate_sentence(10, Backoff::Longest, &smoother, &sampling, &mut rng)
+            else {
+                continue;
+            };
+            assert_eq!(sentence.first(), Some(&start));
+            assert_eq!(sentence.last(), Some(&end));
+            let length = sentence.iter().filter(|&&t| t != start && t != end);
+            assert!(length.count() <= 10);
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    /// The state of `tokens`, which must all be in the vocabulary.
+    pub fn state_of(markov: &MarkovGenerator, tokens: &[&str]) -> State {
+        State::new(tokens.iter().map(|t| markov.token_id(t).unwrap()).collect())
+    }
+
+    #[test]
+    fn backoff_only_shortens_unseen_states_when_asked() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        // "nobler" is only ever followed by "in", but "to" is followed by plenty
+        let state = state_of(&markov, &["nobler", "to"]);
+        assert!(markov.transitions(&state).is_empty());
+
+        assert!(markov
+            .successors(&state, Backoff::None, &smoother)
+            .is_empty());
+
+        let suffix = markov.transitions(&state_of(&markov, &["to"]));
+        let total: Count = suffix.iter().map(|&(_, count)| count).sum();
+        let longest = markov.successors(&state, Backoff::Longest, &smoother);
+        assert_eq!(longest.len(), suffix.len());
+        for (&(token, weight), &(expected, count)) in longest.iter().zip(&suffix) {
+            assert_eq!(token, expected);
+            assert!((weight - count as f64 / total as f64).abs() < 1e-12);
+        }
+
+        // Stupid backoff scales down the shorter state it had to fall back to
+        let stupid = markov.successors(&state, Backoff::Stupid(0.5), &smoother);
+        assert_eq!(stupid.len(), longest.len());
+        for (&(token, weight), &(expected, unscaled)) in stupid.iter().zip(&longest) {
+            assert_eq!(token, expected);
+            assert!((weight - 0.5 * unscaled).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn stupid_backoff_adds_the_successors_of_shorter_states() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        let state = state_of(&markov, &["to", "sleep"]);
+
+        let longest = markov.successors(&state, Backoff::Longest, &smoother);
+        let stupid = markov.successors(&state, Backoff::Stupid(0.4), &smoother);
+        assert!(!longest.is_empty());
+        assert!(stupid.len() > longest.len());
+        assert!(stupid[..longest.len()] == longest[..]);
+
+        let suffix = markov.transitions(&state_of(&markov, &["sleep"]));
+        let total: Count = suffix.iter().map(|&(_, count)| count).sum();
+        for &(token, weight) in &stupid[longest.len()..] {
+            assert!(longest.iter().all(|&(t, _)| t != token));
+            let count = suffix.iter().find(|&&(t, _)| t == token).unwrap().1;
+            assert!((weight - 0.4 * count as f64 / total as f64).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn backoff_factors_must_be_probabilities() {
+        for factor in ["-5", "0", "1.5", "NaN"] {
+            let arg = format!("--backoff-factor={factor}");
+            assert!(Args::try_parse_from(["markov", &arg]).is_err(), "{factor}");
+        }
+        assert!(Args::try_parse_from(["markov", "--backoff-factor=1"]).is_ok());
+    }
+
+    #[test]
+    fn training_again_adds_to_the_counts() {
+        let mut markov = trained(2);
+        let before = transitions(&markov);
+        let tokens = markov.metadata.tokens;
+
+        markov
+            .train(&mut TEXT.as_bytes(), &tokenizer::Words)
+            .unwrap();
+        let doubled: Vec<_> = before
+            .into_iter()
+            .map(|(from, to, count)| (from, to, count * 2))
+            .collect();
+        assert_eq!(transitions(&markov), doubled);
+        assert_eq!(markov.metadata.tokens, tokens * 2);
+
+        // New text adds its own states next to the existing ones
+        let states = markov.chain().states.len();
+        markov
+            .train(
+                &mut "Words never seen before.".as_bytes(),
+                &tokenizer::Words,
+            )
+            .unwrap();
+        assert!(markov.chain().states.len() > states);
+        assert!(markov.token_id("never").is_some());
+    }
+
+    #[test]
+    fn merging_scales_the_other_counts() {
+        let other = trained(2);
+        let before = transitions(&other);
+
+        let mut merged = trained(2);
+        merged.merge(&other, 2.0);
+        let tripled: Vec<_> = before
+            .iter()
+            .map(|(from, to, count)| (from.clone(), to.clone(), count * 3))
+            .collect();
+        assert_eq!(transitions(&merged), tripled);
+
+        // Scaled down counts are rounded, but never to 0
+        let mut merged = trained(2);
+        merged.merge(&other, 0.1);
+        let expected: Vec<_> = before
+            .iter()
+            .map(|(from, to, count)| {
+                let scaled = ((*count as f64 * 0.1).round() as Count).max(1);
+                (from.clone(), to.clone(), count + scaled)
+            })
+            .collect();
+        assert_eq!(transitions(&merged), expected);
+    }
+
+    #[test]
+    fn merging_matches_states_by_their_tokens() {
+        let mut other = MarkovGenerator::new(2);
+        other
+            .train(
+                &mut "Whether to sleep, or to be.".as_bytes(),
+                &tokenizer::Words,
+            )
+            .unwrap();
+
+        let mut expected = HashMap::new();
+        for (from, to, count) in transitions(&trained(2))
+            .into_iter()
+            .chain(transitions(&other))
+        {
+            *expected.entry((from, to)).or_insert(0) += count;
+        }
+        let mut expected: Vec<_> = expected
+            .into_iter()
+            .map(|((from, to), count)| (from, to, count))
+            .collect();
+        expected.sort();
+
+        let mut merged = trained(2);
+        merged.merge(&other, 1.0);
+        assert_eq!(transitions(&merged), expected);
+        assert_eq!(
+            merged.metadata.tokens,
+            trained(2).metadata.tokens + other.metadata.tokens
+        );
+    }
+}
@@ -0,0 +1,736 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom, Write},
+    marker::PhantomData,
+    ops::{Deref, Range},
+    path::Path,
+};
+
+use indexmap::IndexSet;
+use memmap2::Mmap;
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
+
+use crate::{
+    file::{invalid_data, unsupported_version, verify_checksum, Header},
+    smoothing::Smoothing,
+    Chain, Count, CountWidth, MarkovGenerator, Model, State, StateIndex, TokenId, TokenizerOptions,
+    Vocabulary, SENTENCE_START,
+};
+
+/// Magic numbers of matrix files laid out to be memory mapped and queried in
+/// place, rather than deserialized.
+pub static MAPPED_MAGIC_FILE_BYTES: [u8; 3] = [0x3, 0x4, 0x7];
+
+/// Version of the layout written after the magic numbers. Files with a newer
+/// version than this are rejected.
+const MAPPED_FORMAT_VERSION: u16 = 1;
+
+/// Integers stored little endian in a mapped file.
+trait Int: Copy + 'static {
+    const WIDTH: usize;
+
+    fn from_bytes(bytes: &[u8]) -> Self;
+
+    fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()>;
+}
+
+macro_rules! impl_int {
+    ($($t:ty),*) => {$(
+        impl Int for $t {
+            const WIDTH: usize = std::mem::size_of::<$t>();
+
+            fn from_bytes(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+
+            fn write_to<W: Write>(self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+        }
+    )*};
+}
+
+impl_int!(u8, u16, u32, u64);
+
+/// Where an array of `T` is in the file. Arrays are written as their length
+/// followed by their elements.
+struct Array<T> {
+    start: usize,
+    len: usize,
+    _marker: PhantomData<T>,
+}
+
+fn write_array<T: Int, W: Write>(
+    writer: &mut W,
+    values: impl ExactSizeIterator<Item = T>,
+) -> io::Result<()> {
+    (values.len() as u64).write_to(writer)?;
+    for value in values {
+        value.write_to(writer)?;
+    }
+    Ok(())
+}
+
+/// Reads where each array is, without reading the arrays themselves.
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn array<T: Int>(&mut self) -> io::Result<Array<T>> {
+        let truncated = || invalid_data("Invalid matrix file: it is truncated".into());
+        let len_bytes = self
+            .bytes
+            .get(self.pos..self.pos + 8)
+            .ok_or_else(truncated)?;
+        let len = usize::try_from(u64::from_bytes(len_bytes)).map_err(|_| truncated())?;
+        let start = self.pos + 8;
+        self.pos = len
+            .checked_mul(T::WIDTH)
+            .and_then(|size| start.checked_add(size))
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(truncated)?;
+
+        Ok(Array {
+            start,
+            len,
+            _marker: PhantomData,
+        })
+    }
+}
+
+/// The chain of states of `order` tokens.
+struct MappedChain {
+    order: usize,
+    /// The tokens of every state, one after the other.
+    states: Array<u32>,
+    /// Indices of the states, sorted by their tokens so they can be searched.
+    sorted_states: Array<u32>,
+    indptr: Array<u64>,
+    indices: Array<u32>,
+    counts: Counts,
+}
+
+/// Transition counts, stored in as many bits as the generator's count width.
+enum Counts {
+    U16(Array<u16>),
+    U32(Array<u32>),
+    U64(Array<u64>),
+}
+
+impl Counts {
+    fn len(&self) -> usize {
+        match self {
+            Counts::U16(array) => array.len,
+            Counts::U32(array) => array.len,
+            Counts::U64(array) => array.len,
+        }
+    }
+}
+
+impl MappedChain {
+    fn n_states(&self) -> usize {
+        self.sorted_states.len
+    }
+}
+
+/// A generator saved with [`write_mapped`], read directly from the bytes of the
+/// file. Only the header is deserialized up front, everything else is read as
+/// it is needed.
+pub struct MappedGenerator<B = Mmap> {
+    bytes: B,
+    header: Header,
+    /// Where each token starts in `token_bytes`, plus where the last one ends.
+    token_offsets: Array<u64>,
+    token_bytes: Array<u8>,
+    /// Token ids sorted by their text, so tokens can be searched.
+    sorted_tokens: Array<u32>,
+    chains: Vec<MappedChain>,
+}
+
+/// Save `markov` to `path` in the layout read by [`MappedGenerator`].
+pub fn write_mapped(path: &Path, markov: &MarkovGenerator) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&MAPPED_MAGIC_FILE_BYTES)?;
+    file.write_all(&MAPPED_FORMAT_VERSION.to_le_bytes())?;
+    // Placeholder for the checksum, written once everything else has been
+    file.write_all(&[0; 4])?;
+
+    let mut writer = ChecksumWriter {
+        inner: BufWriter::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+
+    let header = postcard::to_allocvec(&Header::new(markov)).unwrap();
+    write_array(&mut writer, header.into_iter())?;
+
+    let mut offset = 0;
+    let offsets = std::iter::once(0).chain(markov.vocab.iter().map(|token| {
+        offset += token.len() as u64;
+        offset
+    }));
+    write_array(&mut writer, offsets.collect::<Vec<_>>().into_iter())?;
+    let token_bytes: Vec<u8> = markov.vocab.iter().flat_map(str::bytes).collect();
+    write_array(&mut writer, token_bytes.into_iter())?;
+    let mut sorted_tokens: Vec<u32> = (0..markov.vocab.len() as u32).collect();
+    sorted_tokens.sort_by_key(|&id| markov.vocab.get_token(id).unwrap());
+    write_array(&mut writer, sorted_tokens.into_iter())?;
+
+    for chain in &markov.chains {
+        let n_states = chain.states.len();
+        let states: Vec<u32> = (0..n_states)
+            .flat_map(|i| chain.states.get_state(i).unwrap().iter().copied())
+            .collect();
+        write_array(&mut writer, states.into_iter())?;
+
+        let mut sorted_states: Vec<u32> = (0..n_states as u32).collect();
+        sorted_states.sort_by_key(|&i| &**chain.states.get_state(i as usize).unwrap());
+        write_array(&mut writer, sorted_states.into_iter())?;
+
+        let indptr = chain.mat.indptr();
+        let indptr = indptr.to_proper();
+        write_array(&mut writer, indptr.iter().map(|&i| i as u64))?;
+        write_array(&mut writer, chain.mat.indices().iter().map(|&i| i as u32))?;
+        // Counts are already capped at the count width, so these casts can't truncate
+        let counts = chain.mat.data().iter();
+        match markov.metadata.count_width {
+            CountWidth::U16 => write_array(&mut writer, counts.map(|&c| c as u16))?,
+            CountWidth::U32 => write_array(&mut writer, counts.map(|&c| c as u32))?,
+            CountWidth::U64 => write_array(&mut writer, counts.copied())?,
+        }
+    }
+
+    let checksum = writer.hasher.finalize();
+    let mut file = writer
+        .inner
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)?;
+    file.seek(SeekFrom::Start(
+        (MAPPED_MAGIC_FILE_BYTES.len() + size_of::<u16>()) as u64,
+    ))?;
+    file.write_all(&checksum.to_le_bytes())
+}
+
+/// Passes everything written on to `inner`, keeping a checksum of it.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl MappedGenerator {
+    /// Map the generator saved at `path`. Returns `None` if the file isn't in the
+    /// mapped layout.
+    pub fn open(path: &Path) -> io::Result<Option<Self>> {
+        let mut file = File::open(path)?;
+        let mut magic = [0; 3];
+        if file.read_exact(&mut magic).is_err() || magic != MAPPED_MAGIC_FILE_BYTES {
+            return Ok(None);
+        }
+
+        // SAFETY: this is only sound as long as nothing else changes the file
+        // while it is mapped, which we can't prevent. If it is truncated, reading
+        // the missing pages kills the process with SIGBUS, and if it is modified
+        // the behaviour is undefined. Bounds checks don't help with either.
+        let mmap = unsafe { Mmap::map(&file)? };
+        // The checksum isn't verified, as that would mean hashing the whole file
+        Self::from_bytes(mmap).map(Some)
+    }
+}
+
+impl<B> MappedGenerator<B>
+where
+    B: Deref<Target = [u8]>,
+{
+    /// Find where everything is in `bytes`, which start with the magic numbers.
+    fn from_bytes(bytes: B) -> io::Result<Self> {
+        let version_start = MAPPED_MAGIC_FILE_BYTES.len();
+        let Some(version) = bytes.get(version_start..version_start + 2) else {
+            return Err(invalid_data("Invalid matrix file: missing version".into()));
+        };
+        let version = u16::from_bytes(version);
+        if version != MAPPED_FORMAT_VERSION {
+            return Err(unsupported_version(version, MAPPED_FORMAT_VERSION));
+        }
+
+        let mut parser = Parser {
+            bytes: &bytes,
+            pos: version_start + 2 + 4,
+        };
+        let header = parser.array::<u8>()?;
+        let header: Header = postcard::from_bytes(&bytes[header.start..][..header.len])
+            .map_err(|e| invalid_data(format!("Invalid matrix file: {e}")))?;
+
+        let token_offsets = parser.array()?;
+        let token_bytes = parser.array()?;
+        let sorted_tokens: Array<u32> = parser.array()?;
+        if token_offsets.len != sorted_tokens.len + 1 {
+            return Err(invalid_data(
+                "Invalid matrix file: the vocabulary arrays have different lengths".into(),
+            ));
+        }
+
+        // The state size hasn't been checked yet, so don't allocate for it up front
+        let mut chains = Vec::new();
+        for order in 1..=header.state_size as usize {
+            let chain = MappedChain {
+                order,
+                states: parser.array()?,
+                sorted_states: parser.array()?,
+                indptr: parser.array()?,
+                indices: parser.array()?,
+                counts: match header.count_width {
+                    CountWidth::U16 => Counts::U16(parser.array()?),
+                    CountWidth::U32 => Counts::U32(parser.array()?),
+                    CountWidth::U64 => Counts::U64(parser.array()?),
+                },
+            };
+
+            let n_states = chain.n_states();
+            if chain.states.len != n_states * order
+                || chain.indptr.len != n_states + 1
+                || chain.indices.len != chain.counts.len()
+            {
+                return Err(invalid_data(format!(
+                    "Invalid matrix file: the arrays of chain {order} have inconsistent lengths"
+                )));
+            }
+            chains.push(chain);
+        }
+
+        if let Some(chain) = chains.last() {
+            if header.states != chain.n_states() as u64 {
+                return Err(invalid_data(format!(
+                    "Invalid matrix file: the header has {} states, but the matrix has {}",
+                    header.states,
+                    chain.n_states()
+                )));
+            }
+        }
+
+        let mapped = Self {
+            bytes,
+            header,
+            token_offsets,
+            token_bytes,
+            sorted_tokens,
+            chains,
+        };
+        // Only what can be checked without reading the arrays is checked here.
+        // Everything else is checked as it's read, so opening a file stays cheap.
+        for chain in &mapped.chains {
+            let first = mapped.get(&chain.indptr, 0);
+            let last = mapped.get(&chain.indptr, chain.n_states());
+            if first != Some(0) || last != Some(chain.indices.len as u64) {
+                return Err(invalid_data(format!(
+                    "Invalid matrix file: the rows of chain {} don't cover its transitions",
+                    chain.order
+                )));
+            }
+        }
+        Ok(mapped)
+    }
+
+    /// The `i`th element of `array`, or `None` if it's out of bounds.
+    fn get<T: Int>(&self, array: &Array<T>, i: usize) -> Option<T> {
+        self.get_range(array, i..i.checked_add(1)?)?.next()
+    }
+
+    /// The elements of `array` in `range`, or `None` if it's out of bounds.
+    fn get_range<T: Int>(
+        &self,
+        array: &Array<T>,
+        range: Range<usize>,
+    ) -> Option<impl Iterator<Item = T> + '_> {
+        if range.start > range.end || range.end > array.len {
+            return None;
+        }
+        let bytes = &self.bytes[array.start..][range.start * T::WIDTH..range.end * T::WIDTH];
+        Some(bytes.chunks_exact(T::WIDTH).map(T::from_bytes))
+    }
+
+    /// The text of token `id`, or `None` if it or its offsets are out of range.
+    fn token_bytes(&self, id: TokenId) -> Option<&[u8]> {
+        let start = self.get(&self.token_offsets, id as usize)? as usize;
+        let end = self.get(&self.token_offsets, id as usize + 1)? as usize;
+        self.bytes[self.token_bytes.start..][..self.token_bytes.len].get(start..end)
+    }
+
+    /// The tokens of the `index`th state of `chain`.
+    fn state_ids(
+        &self,
+        chain: &MappedChain,
+        index: usize,
+    ) -> Option<impl Iterator<Item = TokenId> + '_> {
+        let start = index.checked_mul(chain.order)?;
+        self.get_range(&chain.states, start..start.checked_add(chain.order)?)
+    }
+
+    fn state(&self, chain: &MappedChain, index: usize) -> Option<State> {
+        Some(State::new(self.state_ids(chain, index)?.collect()))
+    }
+
+    fn state_index(&self, chain: &MappedChain, state: &State) -> Option<usize> {
+        let position = search(chain.n_states(), |i| {
+            let index = self.get(&chain.sorted_states, i)? as usize;
+            Some(self.state_ids(chain, index)?.cmp(state.iter().copied()))
+        })?;
+        let index = self.get(&chain.sorted_states, position)? as usize;
+        (index < chain.n_states()).then_some(index)
+    }
+
+    fn count(&self, chain: &MappedChain, i: usize) -> Option<Count> {
+        match &chain.counts {
+            Counts::U16(array) => self.get(array, i).map(Count::from),
+            Counts::U32(array) => self.get(array, i).map(Count::from),
+            Counts::U64(array) => self.get(array, i),
+        }
+    }
+
+    /// The range of `chain.indices` and `chain.counts` holding the row of `index`.
+    fn row(&self, chain: &MappedChain, index: usize) -> Option<Range<usize>> {
+        let start = self.get(&chain.indptr, index)? as usize;
+        let end = self.get(&chain.indptr, index.checked_add(1)?)? as usize;
+        (start <= end && end <= chain.indices.len).then_some(start..end)
+    }
+
+    /// The last token of each state following the `index`th state of `chain`,
+    /// and how many times it did. Returns `None` if any of them is out of range.
+    fn row_transitions(&self, chain: &MappedChain, index: usize) -> Option<Vec<(TokenId, Count)>> {
+        self.row(chain, index)?
+            .map(|k| {
+                let next = self.get(&chain.indices, k)? as usize;
+                let last = next.checked_add(1)?.checked_mul(chain.order)? - 1;
+                let token = self.get(&chain.states, last)?;
+                let count = self.count(chain, k)?;
+                ((token as usize) < self.vocab_size()).then_some((token, count))
+            })
+            .collect()
+    }
+
+    /// Read everything into memory, to do more than generate text with it.
+    pub fn to_generator(&self) -> io::Result<MarkovGenerator> {
+        let vocab_size = self.vocab_size();
+        let mut vocab = Vocabulary::default();
+        for id in 0..vocab_size as TokenId {
+            let token = self
+                .token_bytes(id)
+                .and_then(|bytes| std::str::from_utf8(bytes).ok())
+                .ok_or_else(|| {
+                    invalid_data(format!("Invalid matrix file: token {id} is malformed"))
+                })?;
+            vocab.intern(token);
+        }
+
+        let mut chains = Vec::with_capacity(self.chains.len());
+        for mapped in &self.chains {
+            let malformed = |e: &dyn std::fmt::Display| {
+                invalid_data(format!(
+                    "Invalid matrix file: chain {} is malformed: {e}",
+                    mapped.order
+                ))
+            };
+
+            let n_states = mapped.n_states();
+            let mut states = IndexSet::with_capacity(n_states);
+            for i in 0..n_states {
+                let state = self.state(mapped, i).unwrap();
+                if state.iter().any(|&id| id as usize >= vocab_size) {
+                    return Err(malformed(&format!("state {i} has an unknown token")));
+                }
+                states.insert(state);
+            }
+
+            // Every array is in bounds of the file, as checked when opening it
+            let indptr = self.get_range(&mapped.indptr, 0..mapped.indptr.len);
+            let indices = self.get_range(&mapped.indices, 0..mapped.indices.len);
+            let counts = (0..mapped.counts.len()).map(|i| self.count(mapped, i).unwrap());
+            let mat = sprs::CsMat::try_new(
+                (n_states, n_states),
+                indptr.unwrap().map(|i| i as usize).collect(),
+                indices.unwrap().map(|i| i as usize).collect(),
+                counts.collect(),
+            )
+            .map_err(|(_, _, _, e)| malformed(&e))?;
+            chains.push(Chain { mat, states });
+        }
+
+        Ok(MarkovGenerator {
+            vocab,
+            chains,
+            state_size: self.header.state_size,
+            metadata: self.header.clone().into_metadata(),
+        })
+    }
+}
+
+/// Binary search `0..len` for the position where `compare` gives `Equal`.
+/// Gives up if `compare` gives `None`.
+fn search(len: usize, compare: impl Fn(usize) -> Option<Ordering>) -> Option<usize> {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        match compare(mid)? {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Some(mid),
+        }
+    }
+    None
+}
+
+/// Read a generator in the mapped layout into memory, verifying its checksum.
+/// `bytes` start with the magic numbers.
+pub fn read_mapped(bytes: Vec<u8>) -> io::Result<MarkovGenerator> {
+    let checksum_start = MAPPED_MAGIC_FILE_BYTES.len() + size_of::<u16>();
+    let Some(checksum) = bytes.get(checksum_start..checksum_start + 4) else {
+        return Err(invalid_data("Invalid matrix file: missing checksum".into()));
+    };
+    verify_checksum(u32::from_bytes(checksum), &bytes[checksum_start + 4..])?;
+
+    MappedGenerator::from_bytes(bytes)?.to_generator()
+}
+
+/// Out of range offsets and indices are only found when they are read, which
+/// is after the file was opened. Queries reading them behave as if what they
+/// were looking for isn't there, and the text of tokens they lead to is `None`.
+impl<B> Model for MappedGenerator<B>
+where
+    B: Deref<Target = [u8]>,
+{
+    fn state_size(&self) -> u32 {
+        self.header.state_size
+    }
+
+    fn state_count(&self) -> usize {
+        self.chains.last().map_or(0, MappedChain::n_states)
+    }
+
+    fn token_id(&self, token: &str) -> Option<TokenId> {
+        let position = search(self.sorted_tokens.len, |i| {
+            let id = self.get(&self.sorted_tokens, i)?;
+            Some(self.token_bytes(id)?.cmp(token.as_bytes()))
+        })?;
+        self.get(&self.sorted_tokens, position)
+    }
+
+    fn token(&self, id: TokenId) -> Option<&str> {
+        std::str::from_utf8(self.token_bytes(id)?).ok()
+    }
+
+    fn vocab_size(&self) -> usize {
+        self.sorted_tokens.len
+    }
+
+    fn smoothing(&self) -> Smoothing {
+        self.header.smoothing
+    }
+
+    fn tokenizer(&self) -> &TokenizerOptions {
+        &self.header.tokenizer
+    }
+
+    fn find_state(&self, state: &State) -> Option<usize> {
+        let chain = self.chains.get(state.len().checked_sub(1)?)?;
+        self.state_index(chain, state)
+    }
+
+    fn in_degrees(&self, order: usize) -> Vec<u32> {
+        let chain = &self.chains[order - 1];
+        let mut in_degrees = vec![0; chain.n_states()];
+        for next in self
+            .get_range(&chain.indices, 0..chain.indices.len)
+            .unwrap()
+        {
+            if let Some(in_degree) = in_degrees.get_mut(next as usize) {
+                *in_degree += 1;
+            }
+        }
+        in_degrees
+    }
+
+    fn transitions(&self, state: &State) -> Vec<(TokenId, Count)> {
+        let Some(chain) = state.len().checked_sub(1).and_then(|i| self.chains.get(i)) else {
+            return Vec::new();
+        };
+        self.state_index(chain, state)
+            .and_then(|index| self.row_transitions(chain, index))
+            .unwrap_or_default()
+    }
+
+    fn random_state<R: Rng + ?Sized>(&self, rng: &mut R) -> State {
+        let chain = self.chains.last().unwrap();
+        // The states array holds exactly this many states, as checked when opening
+        self.state(chain, rng.random_range(0..chain.n_states()))
+            .unwrap()
+    }
+
+    fn random_sentence_start<R: Rng + ?Sized>(&self, rng: &mut R) -> Option<State> {
+        let start = self.token_id(SENTENCE_START)?;
+        let chain = self.chains.last().unwrap();
+
+        // States starting with the sentence marker are next to each other when
+        // sorted. Put them back in index order to pick the same one as a loaded
+        // generator would.
+        let first_token = |i| {
+            let index = self.get(&chain.sorted_states, i)? as usize;
+            self.state_ids(chain, index)?.next()
+        };
+        let (mut low, mut high) = (0, chain.n_states());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if first_token(mid) < Some(start) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let mut indices: Vec<usize> = (low..chain.n_states())
+            .take_while(|&i| first_token(i) == Some(start))
+            .filter_map(|i| self.get(&chain.sorted_states, i))
+            .map(|i| i as usize)
+            .collect();
+        indices.sort_unstable();
+
+        let weights = indices.iter().map(|&i| {
+            let count = self
+                .row(chain, i)
+                .and_then(|row| row.map(|k| self.count(chain, k)).sum::<Option<Count>>());
+            count.unwrap_or(0).max(1)
+        });
+        let dist = WeightedIndex::new(weights).ok()?;
+        self.state(chain, indices[dist.sample(rng)])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        file::write_model,
+        tests::{generate_tokens, temp_path, trained},
+    };
+
+    #[test]
+    fn mapped_generates_the_same_tokens() {
+        let markov = trained(2);
+        let path = temp_path("same-tokens.mmap");
+        write_model(&path, &markov).unwrap();
+        let mapped = MappedGenerator::open(&path).unwrap().unwrap();
+
+        for smoothing in [Smoothing::None, Smoothing::KneserNey(0.75)] {
+            for seed in 0..5 {
+                assert_eq!(
+                    generate_tokens(&mapped, smoothing, seed, 100),
+                    generate_tokens(&markov, smoothing, seed, 100)
+                );
+            }
+        }
+        drop(mapped);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn huge_state_sizes_are_rejected() {
+        let markov = trained(2);
+        let bytes = mapped_bytes(&markov, "huge-state-size.mmap");
+
+        // Swap the header for one claiming far more chains than the file has
+        let mut header = Header::new(&markov);
+        header.state_size = u32::MAX;
+        let header = postcard::to_allocvec(&header).unwrap();
+        let header_start = MAPPED_MAGIC_FILE_BYTES.len() + 2 + 4;
+        let old_len = u64::from_bytes(&bytes[header_start..][..8]) as usize;
+        let mut corrupted = bytes[..header_start].to_vec();
+        write_array(&mut corrupted, header.into_iter()).unwrap();
+        corrupted.extend_from_slice(&bytes[header_start + 8 + old_len..]);
+
+        let error = MappedGenerator::from_bytes(corrupted).err().unwrap();
+        assert!(error.to_string().contains("truncated"), "{error}");
+    }
+
+    /// The bytes of `markov` saved in the mapped layout.
+    fn mapped_bytes(markov: &MarkovGenerator, name: &str) -> Vec<u8> {
+        let path = temp_path(name);
+        write_model(&path, markov).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn out_of_range_indices_are_not_followed() {
+        let markov = trained(2);
+        let bytes = mapped_bytes(&markov, "out-of-range.mmap");
+
+        // Opening doesn't read the indices, so point all of them out of range
+        let indices = &MappedGenerator::from_bytes(bytes.clone()).unwrap().chains[1].indices;
+        let mut corrupted = bytes;
+        for i in 0..indices.len {
+            corrupted[indices.start + i * 4..][..4].copy_from_slice(&0xfffffff0u32.to_le_bytes());
+        }
+        let mapped = MappedGenerator::from_bytes(corrupted).unwrap();
+
+        for state in markov.chain().states.iter() {
+            assert!(mapped.transitions(state).is_empty());
+        }
+        // Generating backs off to the shorter states, which are intact
+        for smoothing in [Smoothing::None, Smoothing::KneserNey(0.75)] {
+            assert_eq!(generate_tokens(&mapped, smoothing, 0, 100).len(), 100);
+        }
+    }
+
+    #[test]
+    fn out_of_range_token_offsets_have_no_text() {
+        let markov = trained(2);
+        let mut bytes = mapped_bytes(&markov, "out-of-range-tokens.mmap");
+
+        let offsets = &MappedGenerator::from_bytes(bytes.clone())
+            .unwrap()
+            .token_offsets;
+        bytes[offsets.start + 8..][..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mapped = MappedGenerator::from_bytes(bytes).unwrap();
+
+        assert!(mapped.token(0).is_none());
+        assert!(mapped.token(1).is_none());
+        assert_eq!(mapped.token(2), markov.token(2));
+        assert!(mapped.to_generator().is_err());
+    }
+
+    #[test]
+    fn rows_not_covering_the_transitions_are_rejected() {
+        let mut bytes = mapped_bytes(&trained(2), "uncovered-rows.mmap");
+
+        let indptr = &MappedGenerator::from_bytes(bytes.clone()).unwrap().chains[1].indptr;
+        let last = indptr.start + (indptr.len - 1) * 8;
+        bytes[last..][..8].copy_from_slice(&0u64.to_le_bytes());
+        let error = MappedGenerator::from_bytes(bytes).err().unwrap();
+        assert!(error.to_string().contains("don't cover"), "{error}");
+    }
+
+    #[test]
+    fn newer_versions_are_rejected() {
+        let mut bytes = mapped_bytes(&trained(2), "newer.mmap");
+
+        bytes[MAPPED_MAGIC_FILE_BYTES.len()..][..2]
+            .copy_from_slice(&(MAPPED_FORMAT_VERSION + 1).to_le_bytes());
+        let error = MappedGenerator::from_bytes(bytes).err().unwrap();
+        assert!(error.to_string().contains("newer version"), "{error}");
+    }
+}
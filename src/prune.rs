@@ -0,0 +1,229 @@
+use std::fmt::Display;
+
+use crate::{Chain, Count, MarkovGeneratorBase, StateIndex, TransitionCounts};
+
+pub struct PruneOptions {
+    /// Transitions seen fewer times than this are removed.
+    pub min_count: Count,
+    /// Only keep this many of the most frequent successors of each state.
+    pub max_successors: Option<usize>,
+    /// Remove states which no transition leads to anymore, unless nothing led to
+    /// them before pruning either.
+    pub drop_unreachable: bool,
+}
+
+/// How much was removed from the chain of states of `order` tokens.
+pub struct ChainPruned {
+    pub order: usize,
+    pub transitions_before: usize,
+    pub transitions_after: usize,
+    pub states_before: usize,
+    pub states_after: usize,
+}
+
+/// How much was removed from each chain of a generator.
+pub struct Pruned(pub Vec<ChainPruned>);
+
+impl<S> Chain<S>
+where
+    S: StateIndex + Default,
+{
+    /// Remove transitions and states according to `options`. The remaining
+    /// states keep their order, but are renumbered to close the gaps.
+    fn prune(&mut self, options: &PruneOptions, order: usize) -> ChainPruned {
+        let n_states = self.states.len();
+        let mut rows: Vec<Vec<(usize, Count)>> = self
+            .mat
+            .outer_iterator()
+            .map(|row| {
+                let mut row: Vec<(usize, Count)> = row
+                    .iter()
+                    .map(|(to, &count)| (to, count))
+                    .filter(|&(_, count)| count >= options.min_count)
+                    .collect();
+                if let Some(max_successors) = options.max_successors {
+                    if row.len() > max_successors {
+                        row.sort_by(|(_, a), (_, b)| b.cmp(a));
+                        row.truncate(max_successors);
+                        row.sort_by_key(|&(to, _)| to);
+                    }
+                }
+                row
+            })
+            .collect();
+
+        let mut dropped = vec![false; n_states];
+        if options.drop_unreachable {
+            let mut had_incoming = vec![false; n_states];
+            for (_, (_, to)) in self.mat.iter() {
+                had_incoming[to] = true;
+            }
+            let mut incoming = vec![0usize; n_states];
+            for &(to, _) in rows.iter().flatten() {
+                incoming[to] += 1;
+            }
+
+            // Dropping a state removes its transitions too, which can leave more
+            // states unreachable
+            let mut unreachable: Vec<usize> = (0..n_states)
+                .filter(|&i| had_incoming[i] && incoming[i] == 0)
+                .collect();
+            while let Some(i) = unreachable.pop() {
+                dropped[i] = true;
+                for (to, _) in rows[i].drain(..) {
+                    incoming[to] -= 1;
+                    if incoming[to] == 0 && !dropped[to] {
+                        unreachable.push(to);
+                    }
+                }
+            }
+        }
+
+        let mut states = S::default();
+        let mut new_indicies = vec![0; n_states];
+        for (i, &dropped) in dropped.iter().enumerate() {
+            if !dropped {
+                new_indicies[i] = states.len();
+                states.insert(states.len(), self.states.get_state(i).unwrap().clone());
+            }
+        }
+
+        let mut counts = TransitionCounts::default();
+        for (from, row) in rows.into_iter().enumerate() {
+            for (to, count) in row {
+                counts.add(new_indicies[from], new_indicies[to], count);
+            }
+        }
+
+        let transitions_before = self.mat.nnz();
+        self.mat = counts.into_matrix(states.len());
+        self.states = states;
+        ChainPruned {
+            order,
+            transitions_before,
+            transitions_after: self.mat.nnz(),
+            states_before: n_states,
+            states_after: self.states.len(),
+        }
+    }
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Remove rare transitions from every chain, and the states left unreachable
+    /// if `options` asks for it.
+    pub fn prune(&mut self, options: &PruneOptions) -> Pruned {
+        Pruned(
+            self.chains
+                .iter_mut()
+                .enumerate()
+                .map(|(i, chain)| chain.prune(options, i + 1))
+                .collect(),
+        )
+    }
+}
+
+impl Display for Pruned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn percent(removed: usize, before: usize) -> f64 {
+            100.0 * removed as f64 / before.max(1) as f64
+        }
+
+        for (i, chain) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let transitions = chain.transitions_before - chain.transitions_after;
+            let states = chain.states_before - chain.states_after;
+            write!(
+                f,
+                "Order {}: removed {} of {} transitions ({:.1}%) and {} of {} states ({:.1}%)",
+                chain.order,
+                transitions,
+                chain.transitions_before,
+                percent(transitions, chain.transitions_before),
+                states,
+                chain.states_before,
+                percent(states, chain.states_before),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tests::{trained, transitions},
+        tokenizer::Words,
+        MarkovGenerator,
+    };
+
+    #[test]
+    fn rare_transitions_are_removed() {
+        let mut markov = trained(2);
+        let before = transitions(&markov);
+        let states = markov.chain().states.len();
+
+        let options = PruneOptions {
+            min_count: 2,
+            max_successors: None,
+            drop_unreachable: false,
+        };
+        markov.prune(&options);
+        let frequent: Vec<_> = before
+            .into_iter()
+            .filter(|&(_, _, count)| count >= 2)
+            .collect();
+        assert!(!frequent.is_empty());
+        assert_eq!(transitions(&markov), frequent);
+        assert_eq!(markov.chain().states.len(), states);
+    }
+
+    #[test]
+    fn unreachable_states_are_dropped_and_the_rest_renumbered() {
+        // Trained as <s> a c a b a b . </s>, where "a" is followed by "b" most
+        let mut markov = MarkovGenerator::new(1);
+        markov
+            .train(&mut "A c a b a b.".as_bytes(), &Words)
+            .unwrap();
+
+        let options = PruneOptions {
+            min_count: 1,
+            max_successors: Some(1),
+            drop_unreachable: true,
+        };
+        let pruned = markov.prune(&options);
+
+        // Only "a" leads to "c", and only "b" to ".", which is all that leads to
+        // "</s>". "<s>" is kept, as nothing led to it before either.
+        let chain = markov.chain();
+        let states: Vec<_> = (0..chain.states.len())
+            .map(|i| {
+                markov
+                    .state_tokens(chain.states.get_state(i).unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(states, [["<s>"], ["a"], ["b"]]);
+        assert_eq!(chain.mat.shape(), (3, 3));
+
+        let token = |t: &str| vec![t.to_owned()];
+        assert_eq!(
+            transitions(&markov),
+            [
+                (token("<s>"), token("a"), 1),
+                (token("a"), token("b"), 2),
+                (token("b"), token("a"), 1),
+            ]
+        );
+        assert_eq!(
+            pruned.to_string(),
+            "Order 1: removed 4 of 7 transitions (57.1%) and 3 of 6 states (50.0%)"
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use rand::{
+    distr::{weighted::WeightedIndex, Distribution},
+    Rng,
+};
+
+/// Options reshaping the weights of the possible successors of a state before
+/// one of them is picked.
+#[derive(Clone, Copy, Debug)]
+pub struct SamplingConfig {
+    /// Weights are raised to the power of `1 / temperature`, so values below 1
+    /// favour likely successors and values above 1 flatten the distribution. A
+    /// temperature of 0 always picks the most likely successor.
+    pub temperature: f64,
+    /// Only consider the `top_k` most likely successors.
+    pub top_k: Option<usize>,
+    /// Only consider the most likely successors whose probabilities add up to at
+    /// least `top_p`.
+    pub top_p: Option<f64>,
+}
+
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: None,
+            top_p: None,
+        }
+    }
+}
+
+impl SamplingConfig {
+    /// Pick one of `candidates` according to their weights, or `None` if there
+    /// are none to pick from.
+    pub fn sample<T: Copy, R: Rng + ?Sized>(
+        &self,
+        candidates: &[(T, f64)],
+        rng: &mut R,
+    ) -> Option<T> {
+        let max_weight = candidates.iter().map(|&(_, w)| w).fold(0.0, f64::max);
+        if max_weight <= 0.0 {
+            return None;
+        }
+
+        // Sort by descending weight, ties keep their order so greedy picks are stable
+        let mut candidates = candidates.to_vec();
+        candidates.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        if self.temperature == 0.0 {
+            return Some(candidates[0].0);
+        }
+
+        // Normalise against the largest weight first, so small temperatures don't
+        // overflow.
+        for (_, weight) in &mut candidates {
+            *weight = (*weight / max_weight).powf(1.0 / self.temperature);
+        }
+
+        if let Some(k) = self.top_k {
+            candidates.truncate(k.max(1));
+        }
+
+        if let Some(p) = self.top_p {
+            let total: f64 = candidates.iter().map(|&(_, w)| w).sum();
+            let mut cumulative = 0.0;
+            let keep = candidates
+                .iter()
+                .position(|&(_, w)| {
+                    cumulative += w / total;
+                    cumulative >= p
+                })
+                .map_or(candidates.len(), |i| i + 1);
+            candidates.truncate(keep);
+        }
+
+        let dist = WeightedIndex::new(candidates.iter().map(|&(_, w)| w)).ok()?;
+        Some(candidates[dist.sample(rng)].0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    const CANDIDATES: [(char, f64); 4] = [('a', 1.0), ('b', 4.0), ('c', 3.0), ('d', 2.0)];
+
+    /// Every candidate picked in many samples with `config`.
+    fn picked(config: SamplingConfig) -> HashSet<char> {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        (0..1000)
+            .map(|_| config.sample(&CANDIDATES, &mut rng).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn zero_temperature_picks_the_most_likely() {
+        let config = SamplingConfig {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(picked(config), HashSet::from(['b']));
+
+        // Ties go to the first of them
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        assert_eq!(
+            config.sample(&[('x', 1.0), ('y', 1.0)], &mut rng),
+            Some('x')
+        );
+    }
+
+    #[test]
+    fn top_k_keeps_the_k_most_likely() {
+        let config = SamplingConfig {
+            top_k: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(picked(config), HashSet::from(['b', 'c']));
+        assert_eq!(picked(SamplingConfig::default()).len(), 4);
+    }
+
+    #[test]
+    fn top_p_keeps_the_most_likely_reaching_p() {
+        // b and c make up 0.7 of the total
+        let config = |top_p| SamplingConfig {
+            top_p: Some(top_p),
+            ..Default::default()
+        };
+        assert_eq!(picked(config(0.7)), HashSet::from(['b', 'c']));
+        assert_eq!(picked(config(0.71)), HashSet::from(['b', 'c', 'd']));
+        assert_eq!(picked(config(0.1)), HashSet::from(['b']));
+    }
+
+    #[test]
+    fn nothing_is_picked_without_weight() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let config = SamplingConfig::default();
+        assert_eq!(config.sample::<char, _>(&[], &mut rng), None);
+        assert_eq!(config.sample(&[('a', 0.0)], &mut rng), None);
+    }
+}
@@ -0,0 +1,187 @@
+use std::io::{self, BufRead};
+
+use crate::{smoothing::Smoother, tokenizer::Tokenizer, MarkovGeneratorBase, StateIndex};
+
+/// How likely a piece of text is according to a generator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Score {
+    /// Number of tokens scored. The first tokens of the text are only used as
+    /// context, so this is `state_size` less than the number of tokens.
+    pub tokens: usize,
+    /// Sum of the natural log of the probability of each scored token.
+    pub log_prob: f64,
+    /// Number of scored tokens which never followed their state while training.
+    pub unseen: usize,
+}
+
+impl Score {
+    pub fn perplexity(&self) -> f64 {
+        (-self.log_prob / self.tokens as f64).exp()
+    }
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Score how likely the text read from `reader` is to have been generated by
+    /// this generator, splitting it into tokens with `tokenizer`.
+    pub fn score(
+        &self,
+        reader: &mut dyn BufRead,
+        tokenizer: &dyn Tokenizer,
+        smoother: &Smoother,
+    ) -> io::Result<Score> {
+        let tokens = tokenizer.tokenize(reader, self.metadata.tokenizer.sentence_markers)?;
+        let ids: Vec<_> = tokens.iter().map(|t| self.vocab.get_id(t)).collect();
+        let state_size = self.state_size as usize;
+
+        let mut score = Score::default();
+        for window in ids.windows(state_size + 1) {
+            let (context, token) = window.split_at(state_size);
+            let (probability, seen) = smoother.probability(self, context, token[0]);
+            score.tokens += 1;
+            score.log_prob += probability.ln();
+            if !seen {
+                score.unseen += 1;
+            }
+        }
+
+        Ok(score)
+    }
+}
+
+/// Turn the scores of the same text by several generators into probabilities of
+/// each generator having produced it, assuming they're all equally likely to
+/// begin with. Generators with a different state size or tokenizer score a
+/// different number of tokens, so log probabilities are first scaled to the
+/// largest number of tokens scored. If none of the generators could have
+/// produced the text, they're left equally likely.
+pub fn normalize(scores: &[Score]) -> Vec<f64> {
+    let length = scores.iter().map(|s| s.tokens).max().unwrap_or(0) as f64;
+    let log_probs: Vec<f64> = scores
+        .iter()
+        .map(|s| s.log_prob / s.tokens as f64 * length)
+        .collect();
+
+    // Subtract the largest log probability so the exponents don't underflow
+    let max = log_probs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if !max.is_finite() {
+        return vec![1.0 / scores.len() as f64; scores.len()];
+    }
+    let weights: Vec<f64> = log_probs.iter().map(|p| (p - max).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    weights.iter().map(|w| w / total).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        smoothing::Smoothing,
+        tests::{trained, TEXT},
+        tokenizer::Words,
+        Model, State,
+    };
+
+    #[test]
+    fn training_text_is_scored_by_its_transitions() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::None);
+        let score = markov
+            .score(&mut TEXT.as_bytes(), &Words, &smoother)
+            .unwrap();
+
+        let tokens = Words.tokenize(&mut TEXT.as_bytes(), true).unwrap();
+        assert_eq!(score.tokens, tokens.len() - 2);
+        assert_eq!(score.unseen, 0);
+
+        let mut log_prob = 0.0;
+        for window in tokens.windows(3) {
+            let context = window[..2].iter().map(|t| markov.token_id(t).unwrap());
+            let transitions = markov.transitions(&State::new(context.collect()));
+            let total: u64 = transitions.iter().map(|&(_, count)| count).sum();
+            let next = markov.token_id(&window[2]).unwrap();
+            let count = transitions.iter().find(|&&(t, _)| t == next).unwrap().1;
+            log_prob += (count as f64 / total as f64).ln();
+        }
+        assert!((score.log_prob - log_prob).abs() < 1e-9);
+        assert!((score.perplexity() - (-log_prob / score.tokens as f64).exp()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn unseen_transitions_are_counted() {
+        let markov = trained(2);
+        let smoother = Smoother::new(&markov, Smoothing::Additive(1.0));
+        let seen = markov
+            .score(&mut TEXT.as_bytes(), &Words, &smoother)
+            .unwrap();
+        let unseen = markov
+            .score(&mut "To sleep, to be nobler.".as_bytes(), &Words, &smoother)
+            .unwrap();
+
+        assert!(unseen.unseen > 0);
+        assert!(unseen.log_prob.is_finite());
+        assert!(unseen.perplexity() > seen.perplexity());
+    }
+
+    fn score(tokens: usize, log_prob: f64) -> Score {
+        Score {
+            tokens,
+            log_prob,
+            unseen: 0,
+        }
+    }
+
+    #[test]
+    fn normalized_scores_are_probabilities() {
+        let probabilities = normalize(&[score(10, -20.0), score(10, -10.0), score(10, -30.0)]);
+        assert!((probabilities.iter().sum::<f64>() - 1.0).abs() < 1e-12);
+        assert!(probabilities[1] > probabilities[0] && probabilities[0] > probabilities[2]);
+        // e^-10 times as likely as the best
+        assert!((probabilities[0] / probabilities[1] - (-10.0f64).exp()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn scores_of_fewer_tokens_are_scaled_up() {
+        let probabilities = normalize(&[score(10, -20.0), score(5, -10.0)]);
+        assert!((probabilities[0] - 0.5).abs() < 1e-12);
+        assert!((probabilities[1] - 0.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn impossible_texts_leave_every_generator_equally_likely() {
+        let impossible = score(10, f64::NEG_INFINITY);
+        assert_eq!(normalize(&[impossible, impossible]), [0.5, 0.5]);
+        assert_eq!(normalize(&[impossible, score(10, -5.0)]), [0.0, 1.0]);
+        assert_eq!(normalize(&[score(0, 0.0)]), [1.0]);
+    }
+
+    #[test]
+    fn the_generator_trained_on_a_text_is_the_most_likely() {
+        // The same words in a different order, so neither has unknown tokens
+        let mut other = crate::MarkovGenerator::new(2);
+        other
+            .train(
+                &mut "Sleep, perchance. Dream to die to sleep, to die to dream.".as_bytes(),
+                &Words,
+            )
+            .unwrap();
+
+        let markovs = [other, trained(2)];
+        let scores: Vec<Score> = markovs
+            .iter()
+            .map(|markov| {
+                // Smooth lightly, on a text this short add-one smoothing favours
+                // whichever has the smaller vocabulary
+                let smoother = Smoother::new(markov, Smoothing::Additive(0.01));
+                let text = "To die, to sleep, perchance to dream.";
+                markov
+                    .score(&mut text.as_bytes(), &Words, &smoother)
+                    .unwrap()
+            })
+            .collect();
+        let probabilities = normalize(&scores);
+        assert!(probabilities[1] > 0.99, "{probabilities:?}");
+    }
+}
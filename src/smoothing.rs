@@ -0,0 +1,381 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Model, State, TokenId};
+
+/// How to assign a probability to transitions which never happened while
+/// training.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Smoothing {
+    /// Use the relative counts as they are, unseen transitions are impossible.
+    #[default]
+    None,
+    /// Add `k` to the count of every possible transition (Laplace smoothing when
+    /// `k` is 1).
+    Additive(f64),
+    /// Interpolate with shorter states, giving them more weight the more distinct
+    /// tokens followed the state. The number of distinct tokens is scaled by the
+    /// parameter.
+    WittenBell(f64),
+    /// Interpolated Kneser-Ney smoothing, which takes the parameter off of every
+    /// count and gives it to shorter states. Shorter states are weighted by how
+    /// many different states lead to each of their successors, rather than how
+    /// often.
+    KneserNey(f64),
+}
+
+impl Smoothing {
+    /// Check that the parameter is in range, for smoothings which weren't parsed
+    /// from a string.
+    pub fn validate(self) -> Result<(), String> {
+        let (name, param) = match self {
+            Self::None => return Ok(()),
+            Self::Additive(k) => ("additive", k),
+            Self::WittenBell(k) => ("witten-bell", k),
+            Self::KneserNey(d) => ("kneser-ney", d),
+        };
+
+        if param.is_nan() || param <= 0.0 {
+            return Err(format!("the parameter of {name} must be above 0"));
+        }
+        if matches!(self, Self::KneserNey(_)) && param > 1.0 {
+            return Err(format!("the parameter of {name} can't be above 1"));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Smoothing {
+    type Err = String;
+
+    /// Parse `none`, or one of `additive`, `witten-bell` or `kneser-ney` with an
+    /// optional parameter, e.g. `additive:0.5`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param)),
+            None => (s, None),
+        };
+        let param = param
+            .map(|p| p.parse::<f64>().map_err(|e| e.to_string()))
+            .transpose()?;
+
+        let smoothing = match (name, param) {
+            ("none", None) => Self::None,
+            ("none", Some(_)) => return Err("none doesn't take a parameter".into()),
+            ("additive", k) => Self::Additive(k.unwrap_or(1.0)),
+            ("witten-bell", k) => Self::WittenBell(k.unwrap_or(1.0)),
+            ("kneser-ney", d) => Self::KneserNey(d.unwrap_or(0.75)),
+            _ => {
+                return Err(format!(
+                    "unknown smoothing {name}, expected none, additive, witten-bell or kneser-ney"
+                ))
+            }
+        };
+        smoothing.validate()?;
+        Ok(smoothing)
+    }
+}
+
+impl Display for Smoothing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Additive(k) => write!(f, "additive:{k}"),
+            Self::WittenBell(k) => write!(f, "witten-bell:{k}"),
+            Self::KneserNey(d) => write!(f, "kneser-ney:{d}"),
+        }
+    }
+}
+
+/// How the counts of what followed a state are mixed with the probabilities
+/// given by the state one token shorter.
+struct Interpolation {
+    /// Taken off of every count before dividing it by `total`.
+    discount: f64,
+    total: f64,
+    /// What the probabilities of the shorter state are scaled by.
+    backoff: f64,
+}
+
+impl Interpolation {
+    fn probability(&self, count: f64) -> f64 {
+        (count - self.discount).max(0.0) / self.total
+    }
+}
+
+/// Computes probabilities of transitions according to a [`Smoothing`], holding
+/// what it needs from the generator up front.
+pub struct Smoother {
+    smoothing: Smoothing,
+    vocab_size: usize,
+    /// Probability of each token regardless of the state, for the smoothings
+    /// which interpolate down to it. The extra last entry is shared by every
+    /// token which isn't in the vocabulary.
+    unigrams: Vec<f64>,
+    /// Number of distinct states leading to each state, by order, for Kneser-Ney.
+    in_degrees: Vec<Vec<u32>>,
+}
+
+impl Smoother {
+    pub fn new<M: Model + ?Sized>(model: &M, smoothing: Smoothing) -> Self {
+        let vocab_size = model.vocab_size();
+        let mut smoother = Self {
+            smoothing,
+            vocab_size,
+            unigrams: Vec::new(),
+            in_degrees: Vec::new(),
+        };
+
+        if let Smoothing::KneserNey(_) = smoothing {
+            smoother.in_degrees = (1..=model.state_size() as usize)
+                .map(|order| model.in_degrees(order))
+                .collect();
+        }
+
+        if let Smoothing::WittenBell(_) | Smoothing::KneserNey(_) = smoothing {
+            // How often each token was followed by something, or for Kneser-Ney
+            // how many different tokens it followed
+            let counts: Vec<(TokenId, f64)> = (0..vocab_size as TokenId)
+                .map(|id| {
+                    let state = State::new([id].into());
+                    let count = match smoothing {
+                        Smoothing::KneserNey(_) => model
+                            .find_state(&state)
+                            .map_or(0, |i| smoother.in_degrees[0][i] as u64),
+                        _ => model.transitions(&state).iter().map(|&(_, c)| c).sum(),
+                    };
+                    (id, count as f64)
+                })
+                .filter(|&(_, count)| count > 0.0)
+                .collect();
+
+            // Interpolate with every token, and one more for unknown ones, being
+            // equally likely
+            let uniform = 1.0 / (vocab_size + 1) as f64;
+            smoother.unigrams = vec![uniform; vocab_size + 1];
+            if let Some(interpolation) = smoother.interpolation(&counts) {
+                for p in &mut smoother.unigrams {
+                    *p *= interpolation.backoff;
+                }
+                for &(id, count) in &counts {
+                    smoother.unigrams[id as usize] += interpolation.probability(count);
+                }
+            }
+        }
+
+        smoother
+    }
+
+    pub fn smoothing(&self) -> Smoothing {
+        self.smoothing
+    }
+
+    /// The tokens which followed `context` and their counts. Kneser-Ney uses how
+    /// many distinct states lead to each successor instead, except for the
+    /// longest state.
+    fn counts<M: Model + ?Sized>(
+        &self,
+        model: &M,
+        context: &[TokenId],
+        longest: bool,
+    ) -> Vec<(TokenId, f64)> {
+        let transitions = model.transitions(&State::new(context.into()));
+        match self.smoothing {
+            Smoothing::KneserNey(_) if !longest => transitions
+                .into_iter()
+                .filter_map(|(token, _)| {
+                    let mut next = context.to_vec();
+                    next.push(token);
+                    let index = model.find_state(&State::new(next.into()))?;
+                    let count = self.in_degrees[context.len()][index];
+                    (count > 0).then_some((token, count as f64))
+                })
+                .collect(),
+            _ => transitions
+                .into_iter()
+                .map(|(token, count)| (token, count as f64))
+                .collect(),
+        }
+    }
+
+    /// How to mix `counts` with shorter states, or `None` if there aren't any.
+    fn interpolation(&self, counts: &[(TokenId, f64)]) -> Option<Interpolation> {
+        let total: f64 = counts.iter().map(|&(_, count)| count).sum();
+        if total == 0.0 {
+            return None;
+        }
+
+        let distinct = counts.len() as f64;
+        let interpolation = match self.smoothing {
+            Smoothing::WittenBell(k) => Interpolation {
+                discount: 0.0,
+                total: total + k * distinct,
+                backoff: k * distinct / (total + k * distinct),
+            },
+            Smoothing::KneserNey(d) => Interpolation {
+                discount: d,
+                total,
+                backoff: d * distinct / total,
+            },
+            Smoothing::None | Smoothing::Additive(_) => Interpolation {
+                discount: 0.0,
+                total,
+                backoff: 0.0,
+            },
+        };
+        Some(interpolation)
+    }
+
+    /// The probability of `token` following `context`. Either may contain tokens
+    /// which aren't in the vocabulary, marked as `None`. Also returns whether the
+    /// transition was seen while training.
+    pub fn probability<M: Model + ?Sized>(
+        &self,
+        model: &M,
+        context: &[Option<TokenId>],
+        token: Option<TokenId>,
+    ) -> (f64, bool) {
+        let count_of = |counts: &[(TokenId, f64)]| {
+            token
+                .and_then(|token| counts.iter().find(|&&(id, _)| id == token))
+                .map_or(0.0, |&(_, count)| count)
+        };
+
+        let (k, interpolate) = match self.smoothing {
+            Smoothing::None => (0.0, false),
+            Smoothing::Additive(k) => (k, false),
+            Smoothing::WittenBell(_) | Smoothing::KneserNey(_) => (0.0, true),
+        };
+
+        if !interpolate {
+            let ids: Option<Vec<TokenId>> = context.iter().copied().collect();
+            let counts = ids.map_or_else(Vec::new, |ids| self.counts(model, &ids, true));
+            let count = count_of(&counts);
+            let total: f64 = counts.iter().map(|&(_, count)| count).sum();
+
+            // Unknown tokens all count as one more entry in the vocabulary
+            let possible_tokens = (self.vocab_size + 1) as f64;
+            let denominator = total + k * possible_tokens;
+            let probability = if denominator == 0.0 {
+                0.0
+            } else {
+                (count + k) / denominator
+            };
+            return (probability, count > 0.0);
+        }
+
+        // Only the end of the context after the last unknown token can be used
+        let start = context
+            .iter()
+            .rposition(Option::is_none)
+            .map_or(0, |i| i + 1);
+        let ids: Vec<TokenId> = context[start..].iter().map(|id| id.unwrap()).collect();
+
+        let mut probability = self.unigrams[token.map_or(self.vocab_size, |id| id as usize)];
+        let mut seen = false;
+        for len in 1..=ids.len() {
+            let longest = len == ids.len();
+            let counts = self.counts(model, &ids[ids.len() - len..], longest);
+            let Some(interpolation) = self.interpolation(&counts) else {
+                continue;
+            };
+
+            let count = count_of(&counts);
+            probability = interpolation.probability(count) + interpolation.backoff * probability;
+            seen = longest && start == 0 && count > 0.0;
+        }
+
+        (probability, seen)
+    }
+
+    /// The probability of every token in the vocabulary following `state`.
+    pub fn distribution<M: Model + ?Sized>(&self, model: &M, state: &State) -> Vec<(TokenId, f64)> {
+        let probabilities = match self.smoothing {
+            Smoothing::None | Smoothing::Additive(_) => {
+                let k = if let Smoothing::Additive(k) = self.smoothing {
+                    k
+                } else {
+                    0.0
+                };
+                let counts = self.counts(model, state, true);
+                let total: f64 = counts.iter().map(|&(_, count)| count).sum();
+                let denominator = total + k * (self.vocab_size + 1) as f64;
+                if denominator == 0.0 {
+                    return Vec::new();
+                }
+
+                let mut probabilities = vec![k / denominator; self.vocab_size];
+                for (id, count) in counts {
+                    probabilities[id as usize] += count / denominator;
+                }
+                probabilities
+            }
+            Smoothing::WittenBell(_) | Smoothing::KneserNey(_) => {
+                let mut probabilities = self.unigrams[..self.vocab_size].to_vec();
+                for len in 1..=state.len() {
+                    let counts =
+                        self.counts(model, &state[state.len() - len..], len == state.len());
+                    let Some(interpolation) = self.interpolation(&counts) else {
+                        continue;
+                    };
+
+                    for p in &mut probabilities {
+                        *p *= interpolation.backoff;
+                    }
+                    for (id, count) in counts {
+                        probabilities[id as usize] += interpolation.probability(count);
+                    }
+                }
+                probabilities
+            }
+        };
+
+        probabilities
+            .into_iter()
+            .enumerate()
+            .map(|(id, p)| (id as TokenId, p))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::trained, StateIndex};
+
+    #[test]
+    fn distributions_sum_to_one() {
+        let markov = trained(3);
+        let smoothings = [
+            Smoothing::None,
+            Smoothing::Additive(0.5),
+            Smoothing::WittenBell(1.0),
+            Smoothing::KneserNey(0.75),
+        ];
+        for smoothing in smoothings {
+            let smoother = Smoother::new(&markov, smoothing);
+            for chain in &markov.chains {
+                for i in 0..chain.states.len() {
+                    let state = chain.states.get_state(i).unwrap();
+                    let distribution = smoother.distribution(&markov, state);
+                    if distribution.is_empty() {
+                        assert_eq!(smoothing, Smoothing::None);
+                        continue;
+                    }
+
+                    // Whatever isn't given to the vocabulary goes to unknown tokens
+                    let context: Vec<_> = state.iter().map(|&id| Some(id)).collect();
+                    let (unknown, _) = smoother.probability(&markov, &context, None);
+                    let total: f64 = distribution.iter().map(|&(_, p)| p).sum();
+                    assert!(
+                        (total + unknown - 1.0).abs() < 1e-9,
+                        "{smoothing} sums to {} after {:?}",
+                        total + unknown,
+                        &**state
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::{Count, MarkovGeneratorBase, StateIndex};
+
+/// Settings for [`MarkovGeneratorBase::stationary_distribution`].
+#[derive(Clone, Copy, Debug)]
+pub struct StationaryOptions {
+    /// Stop once the total change in probability of one iteration is below this.
+    pub tolerance: f64,
+    pub max_iterations: usize,
+    /// Probability of jumping to a random state at each step instead of
+    /// following a transition, like PageRank's damping. Above 0 this makes the
+    /// chain ergodic, so the distribution is unique.
+    pub teleport: f64,
+}
+
+/// The long run probability of being in each state.
+pub struct Stationary {
+    /// Probability of each state, indexed like the state index.
+    pub distribution: Vec<f64>,
+    pub iterations: usize,
+    pub converged: bool,
+    /// States with no successors. Like when generating, these jump to a random
+    /// state, so their probability is spread evenly over every state.
+    pub dead_ends: usize,
+    /// Groups of states which can't be left once entered. With more than one,
+    /// and no teleporting, the chain isn't ergodic and the distribution depends
+    /// on where it started, which is uniformly over every state.
+    pub closed_components: Vec<Vec<usize>>,
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Find the stationary distribution of the full sized states by power
+    /// iteration. The chain is made lazy, staying in place with probability 1/2,
+    /// which doesn't change the stationary distribution but makes sure it is
+    /// converged to even when the chain is periodic.
+    pub fn stationary_distribution(&self, options: StationaryOptions) -> Stationary {
+        let mat = &self.chain().mat;
+        let n_states = mat.rows();
+        let uniform = 1.0 / n_states as f64;
+        let row_totals: Vec<f64> = mat
+            .outer_iterator()
+            .map(|row| row.data().iter().map(|&c| c as f64).sum())
+            .collect();
+
+        let mut distribution = vec![uniform; n_states];
+        let mut next = vec![0.0; n_states];
+        let mut iterations = 0;
+        let mut converged = false;
+        while iterations < options.max_iterations && !converged {
+            iterations += 1;
+            next.fill(0.0);
+
+            let mut jumping = 0.0;
+            for (i, row) in mat.outer_iterator().enumerate() {
+                if row.nnz() == 0 {
+                    jumping += distribution[i];
+                    continue;
+                }
+                for (j, &count) in row.iter() {
+                    next[j] += distribution[i] * count as f64 / row_totals[i];
+                }
+            }
+
+            let mut change = 0.0;
+            for (p, &previous) in next.iter_mut().zip(&distribution) {
+                let stepped = (1.0 - options.teleport) * (*p + jumping * uniform)
+                    + options.teleport * uniform;
+                *p = 0.5 * (stepped + previous);
+                change += (*p - previous).abs();
+            }
+
+            std::mem::swap(&mut distribution, &mut next);
+            converged = change < options.tolerance;
+        }
+
+        let (components, component_of) = strongly_connected_components(mat);
+        let closed_components = components
+            .into_iter()
+            .enumerate()
+            .filter(|(id, component)| is_closed(mat, component, *id, &component_of))
+            .map(|(_, component)| component)
+            .collect();
+
+        Stationary {
+            distribution,
+            iterations,
+            converged,
+            dead_ends: row_totals.iter().filter(|&&t| t == 0.0).count(),
+            closed_components,
+        }
+    }
+}
+
+/// Whether no transition leaves `component`, the one numbered `id` in
+/// `component_of`, ignoring components made of a single dead end.
+fn is_closed(
+    mat: &sprs::CsMat<Count>,
+    component: &[usize],
+    id: usize,
+    component_of: &[usize],
+) -> bool {
+    let mut has_transitions = false;
+    for &state in component {
+        let row = mat.outer_view(state).unwrap();
+        has_transitions |= row.nnz() > 0;
+        if row.indices().iter().any(|&j| component_of[j] != id) {
+            return false;
+        }
+    }
+    has_transitions
+}
+
+/// Tarjan's algorithm, without recursion so long chains don't overflow the stack.
+/// Returns the states of each component, and the component each state is in.
+fn strongly_connected_components(mat: &sprs::CsMat<Count>) -> (Vec<Vec<usize>>, Vec<usize>) {
+    const UNVISITED: usize = usize::MAX;
+    let n_states = mat.rows();
+    let mut index = vec![UNVISITED; n_states];
+    let mut lowlink = vec![0; n_states];
+    let mut on_stack = vec![false; n_states];
+    let mut stack = Vec::new();
+    let mut components = Vec::new();
+    let mut component_of = vec![0; n_states];
+    let mut next_index = 0;
+
+    for root in 0..n_states {
+        if index[root] != UNVISITED {
+            continue;
+        }
+
+        // Each frame is a state and the position of the next successor to visit
+        let mut frames = vec![(root, 0)];
+        index[root] = next_index;
+        lowlink[root] = next_index;
+        next_index += 1;
+        stack.push(root);
+        on_stack[root] = true;
+
+        while let Some(&(state, position)) = frames.last() {
+            let successors = mat.outer_view(state).unwrap();
+            if let Some(&next) = successors.indices().get(position) {
+                frames.last_mut().unwrap().1 += 1;
+                if index[next] == UNVISITED {
+                    index[next] = next_index;
+                    lowlink[next] = next_index;
+                    next_index += 1;
+                    stack.push(next);
+                    on_stack[next] = true;
+                    frames.push((next, 0));
+                } else if on_stack[next] {
+                    lowlink[state] = lowlink[state].min(index[next]);
+                }
+                continue;
+            }
+
+            frames.pop();
+            if let Some(&(parent, _)) = frames.last() {
+                lowlink[parent] = lowlink[parent].min(lowlink[state]);
+            }
+
+            if lowlink[state] == index[state] {
+                let mut component = Vec::new();
+                loop {
+                    let member = stack.pop().unwrap();
+                    on_stack[member] = false;
+                    component_of[member] = components.len();
+                    component.push(member);
+                    if member == state {
+                        break;
+                    }
+                }
+                components.push(component);
+            }
+        }
+    }
+
+    (components, component_of)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        json::{JsonChain, JsonGenerator},
+        MarkovGenerator, Metadata,
+    };
+
+    /// A generator with `n_states` states of one token, and `transitions`
+    /// between them.
+    fn generator(n_states: usize, transitions: &[(usize, usize, Count)]) -> MarkovGenerator {
+        let tokens: Vec<String> = (0..n_states).map(|i| i.to_string()).collect();
+        MarkovGenerator::from_json(JsonGenerator {
+            state_size: 1,
+            metadata: Metadata::default(),
+            vocabulary: tokens.clone(),
+            chains: vec![JsonChain {
+                states: tokens.into_iter().map(|token| vec![token]).collect(),
+                transitions: transitions.to_vec(),
+            }],
+        })
+        .unwrap()
+    }
+
+    const OPTIONS: StationaryOptions = StationaryOptions {
+        tolerance: 1e-12,
+        max_iterations: 10_000,
+        teleport: 0.0,
+    };
+
+    fn assert_close(actual: &[f64], expected: &[f64]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-9, "{actual:?} != {expected:?}");
+        }
+    }
+
+    /// The closed components of `stationary`, sorted so they can be compared.
+    fn closed_components(stationary: Stationary) -> Vec<Vec<usize>> {
+        let mut closed = stationary.closed_components;
+        for component in &mut closed {
+            component.sort();
+        }
+        closed.sort();
+        closed
+    }
+
+    #[test]
+    fn distribution_is_stationary() {
+        // 0 stays or moves to 1 equally often, 1 always goes back
+        let markov = generator(2, &[(0, 0, 1), (0, 1, 1), (1, 0, 1)]);
+        let stationary = markov.stationary_distribution(OPTIONS);
+
+        assert!(stationary.converged);
+        assert_close(&stationary.distribution, &[2.0 / 3.0, 1.0 / 3.0]);
+        assert_eq!(stationary.dead_ends, 0);
+        assert_eq!(closed_components(stationary), [vec![0, 1]]);
+    }
+
+    #[test]
+    fn dead_ends_jump_to_a_random_state() {
+        let markov = generator(2, &[(0, 1, 1)]);
+        let stationary = markov.stationary_distribution(OPTIONS);
+
+        assert_close(&stationary.distribution, &[1.0 / 3.0, 2.0 / 3.0]);
+        assert_eq!(stationary.dead_ends, 1);
+        assert!(stationary.closed_components.is_empty());
+    }
+
+    #[test]
+    fn closed_components_are_found() {
+        // 0 and 1 can't be left, 2 leads into 0
+        let markov = generator(3, &[(0, 0, 1), (1, 1, 1), (2, 0, 1)]);
+        let stationary = markov.stationary_distribution(OPTIONS);
+
+        assert_eq!(closed_components(stationary), [vec![0], vec![1]]);
+
+        // Teleporting reaches every state, even ones nothing leads to
+        let teleporting = StationaryOptions {
+            teleport: 0.15,
+            ..OPTIONS
+        };
+        let stationary = markov.stationary_distribution(teleporting);
+        assert!(stationary.distribution.iter().all(|&p| p > 0.0));
+        assert!((stationary.distribution.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+
+use crate::{MarkovGeneratorBase, StateIndex};
+
+/// Summary of what a generator learned, for its full sized states.
+#[derive(Serialize)]
+pub struct Stats {
+    pub state_size: u32,
+    pub vocabulary_size: usize,
+    /// Number of tokens trained on, or 0 if the matrix was saved before this was
+    /// recorded.
+    pub tokens: u64,
+    /// When the matrix was first trained, in seconds since the Unix epoch, or 0
+    /// if it was saved before this was recorded.
+    pub created: u64,
+    /// Files the matrix was trained on.
+    pub sources: Vec<String>,
+    /// The smoothing saved with the matrix, as given to `--smoothing`.
+    pub smoothing: String,
+    pub states: usize,
+    /// Number of distinct transitions, i.e. non-zero entries in the matrix.
+    pub transitions: usize,
+    /// Fraction of the matrix which is zero.
+    pub sparsity: f64,
+    /// States which were never followed by anything.
+    pub dead_ends: usize,
+    /// How many states have each number of distinct successors, excluding dead
+    /// ends.
+    pub branching_factors: Vec<BranchingFactors>,
+    pub mean_branching_factor: f64,
+    /// Mean Shannon entropy in bits of the successors of each state, excluding
+    /// dead ends.
+    pub mean_entropy: f64,
+    /// The states which were followed by something most often.
+    pub top_states: Vec<StateFrequency>,
+}
+
+/// Number of states with between `min` and `max` distinct successors.
+#[derive(Serialize)]
+pub struct BranchingFactors {
+    pub min: usize,
+    pub max: usize,
+    pub states: usize,
+}
+
+#[derive(Serialize)]
+pub struct StateFrequency {
+    pub state: String,
+    pub count: u64,
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Gather statistics about the full sized states, listing the `top` most
+    /// frequent ones.
+    pub fn stats(&self, top: usize) -> Stats {
+        let chain = self.chain();
+        let n_states = chain.states.len();
+
+        let mut dead_ends = 0;
+        let mut total_entropy = 0.0;
+        let mut branching_factors: Vec<BranchingFactors> = Vec::new();
+        let mut frequencies = Vec::with_capacity(n_states);
+        for (i, row) in chain.mat.outer_iterator().enumerate() {
+            let total: u64 = row.data().iter().sum();
+            frequencies.push((i, total));
+
+            if row.nnz() == 0 {
+                dead_ends += 1;
+                continue;
+            }
+
+            total_entropy -= row
+                .data()
+                .iter()
+                .map(|&c| {
+                    let p = c as f64 / total as f64;
+                    p * p.log2()
+                })
+                .sum::<f64>();
+
+            // Bucket by powers of two: 1, 2, 3-4, 5-8, ...
+            let bucket = row.nnz().next_power_of_two().trailing_zeros() as usize;
+            if branching_factors.len() <= bucket {
+                branching_factors.resize_with(bucket + 1, || BranchingFactors {
+                    min: 0,
+                    max: 0,
+                    states: 0,
+                });
+            }
+            branching_factors[bucket].states += 1;
+        }
+
+        for (bucket, factors) in branching_factors.iter_mut().enumerate() {
+            factors.max = 1 << bucket;
+            factors.min = (1 << bucket >> 1) + 1;
+        }
+
+        frequencies.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let top_states = frequencies
+            .iter()
+            .take(top)
+            .map(|&(i, count)| StateFrequency {
+                state: self
+                    .state_tokens(chain.states.get_state(i).unwrap())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                count,
+            })
+            .collect();
+
+        let nnz = chain.mat.nnz();
+        let live_states = (n_states - dead_ends).max(1) as f64;
+        Stats {
+            state_size: self.state_size,
+            vocabulary_size: self.vocab.len(),
+            tokens: self.metadata.tokens,
+            created: self.metadata.created,
+            sources: self.metadata.sources.clone(),
+            smoothing: self.metadata.smoothing.to_string(),
+            states: n_states,
+            transitions: nnz,
+            sparsity: 1.0 - nnz as f64 / (n_states as f64 * n_states as f64).max(1.0),
+            dead_ends,
+            branching_factors,
+            mean_branching_factor: nnz as f64 / live_states,
+            mean_entropy: total_entropy / live_states,
+            top_states,
+        }
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "State size: {}", self.state_size)?;
+        writeln!(f, "Vocabulary size: {}", self.vocabulary_size)?;
+        if self.tokens > 0 {
+            writeln!(f, "Tokens trained on: {}", self.tokens)?;
+        }
+        if self.created > 0 {
+            writeln!(f, "Created: {}", format_utc(self.created))?;
+        }
+        if !self.sources.is_empty() {
+            writeln!(f, "Sources: {}", self.sources.join(", "))?;
+        }
+        writeln!(f, "Smoothing: {}", self.smoothing)?;
+        writeln!(f, "States: {}", self.states)?;
+        writeln!(f, "Transitions: {}", self.transitions)?;
+        writeln!(f, "Sparsity: {:.4}%", self.sparsity * 100.0)?;
+        writeln!(f, "Dead ends: {}", self.dead_ends)?;
+        writeln!(
+            f,
+            "Mean branching factor: {:.3}",
+            self.mean_branching_factor
+        )?;
+        writeln!(f, "Mean entropy: {:.3} bits", self.mean_entropy)?;
+
+        writeln!(f, "Branching factors:")?;
+        for factors in &self.branching_factors {
+            let range = if factors.min == factors.max {
+                factors.min.to_string()
+            } else {
+                format!("{}-{}", factors.min, factors.max)
+            };
+            writeln!(f, "  {:>11}: {}", range, factors.states)?;
+        }
+
+        write!(f, "Most frequent states:")?;
+        for state in &self.top_states {
+            write!(f, "\n  {:>11}: {}", state.count, state.state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format `seconds` since the Unix epoch as a UTC date and time.
+fn format_utc(seconds: u64) -> String {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+
+    // Civil date from days since the epoch, counting in 400 year eras of 146097
+    // days which start on the 1st of March, so leap days end each year
+    let days = days + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tokenizer::Words, MarkovGenerator};
+
+    #[test]
+    fn stats_describe_the_full_order_chain() {
+        // Trained as <s> a b a c . </s>
+        let mut markov = MarkovGenerator::new(1);
+        markov.train(&mut "A b a c.".as_bytes(), &Words).unwrap();
+        let stats = markov.stats(1);
+
+        assert_eq!(stats.vocabulary_size, 6);
+        assert_eq!(stats.tokens, 7);
+        assert_eq!(stats.states, 6);
+        assert_eq!(stats.transitions, 6);
+        assert!((stats.sparsity - (1.0 - 6.0 / 36.0)).abs() < 1e-12);
+        // Nothing follows the sentence end
+        assert_eq!(stats.dead_ends, 1);
+        // Only "a" has two successors, which are equally likely
+        assert!((stats.mean_branching_factor - 6.0 / 5.0).abs() < 1e-12);
+        assert!((stats.mean_entropy - 1.0 / 5.0).abs() < 1e-12);
+        assert_eq!(stats.branching_factors.len(), 2);
+        assert_eq!(stats.branching_factors[0].states, 4);
+        assert_eq!(stats.branching_factors[1].states, 1);
+        assert_eq!(stats.top_states.len(), 1);
+        assert_eq!(stats.top_states[0].state, "a");
+        assert_eq!(stats.top_states[0].count, 2);
+    }
+
+    #[test]
+    fn times_are_formatted_as_utc_dates() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951868799), "2000-02-29 23:59:59 UTC");
+        assert_eq!(format_utc(1791206219), "2026-10-05 13:16:59 UTC");
+        assert_eq!(format_utc(4107542400), "2100-03-01 00:00:00 UTC");
+    }
+}
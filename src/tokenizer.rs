@@ -0,0 +1,286 @@
+use std::{
+    io::{self, BufRead},
+    sync::{Arc, LazyLock, RwLock},
+};
+
+use unicode_segmentation::UnicodeSegmentation;
+use utf8_chars::BufReadCharsExt;
+
+use crate::{SENTENCE_END, SENTENCE_START};
+
+/// Splits text into tokens to train on, score or continue from.
+pub trait Tokenizer: Send + Sync {
+    /// Split text into tokens. With `sentence_markers`, every sentence is
+    /// surrounded by [`SENTENCE_START`] and [`SENTENCE_END`].
+    fn tokenize(&self, reader: &mut dyn BufRead, sentence_markers: bool)
+        -> io::Result<Vec<String>>;
+
+    /// Split a phrase to continue from into tokens, without sentence markers.
+    fn split(&self, phrase: &str) -> io::Result<Vec<String>> {
+        self.tokenize(&mut phrase.as_bytes(), false)
+    }
+}
+
+/// Joins generated tokens back into text.
+pub trait Detokenizer: Send + Sync {
+    /// Join `tokens` into text. Sentence markers may be among them, and shouldn't
+    /// appear in the text.
+    fn detokenize(&self, tokens: &[String]) -> String;
+}
+
+/// A tokenizer and the detokenizer undoing it, selected with `--tokenizer` and
+/// saved with the matrices trained with them by name.
+#[derive(Clone)]
+pub struct Registered {
+    pub name: String,
+    /// Shown in `--help`.
+    pub help: Option<String>,
+    pub tokenizer: Arc<dyn Tokenizer>,
+    pub detokenizer: Arc<dyn Detokenizer>,
+}
+
+/// Every tokenizer which can be selected by name, starting with the built in
+/// ones. The first is the default.
+static REGISTRY: LazyLock<RwLock<Vec<Registered>>> = LazyLock::new(|| {
+    let builtin = |name: &str, help: &str, tokenizer: Arc<dyn Tokenizer>, detokenizer| Registered {
+        name: name.to_owned(),
+        help: Some(help.to_owned()),
+        tokenizer,
+        detokenizer,
+    };
+    let words = Arc::new(Words);
+    let chars = Arc::new(Characters { graphemes: false });
+    let graphemes = Arc::new(Characters { graphemes: true });
+    RwLock::new(vec![
+        builtin(
+            "words",
+            "Words and runs of punctuation",
+            words.clone(),
+            words,
+        ),
+        builtin(
+            "chars",
+            "Single characters, each line being a sentence",
+            chars.clone(),
+            chars,
+        ),
+        builtin(
+            "graphemes",
+            "Characters as they are displayed, i.e. grapheme clusters, each line being a sentence",
+            graphemes.clone(),
+            graphemes,
+        ),
+    ])
+});
+
+/// Make a tokenizer and its detokenizer available under `name`, replacing any
+/// already registered under it. Tokenizers have to be registered before the
+/// command line is parsed to be selectable with `--tokenizer`, and whenever a
+/// matrix trained with them is loaded.
+pub fn register(name: &str, tokenizer: Box<dyn Tokenizer>, detokenizer: Box<dyn Detokenizer>) {
+    let registered = Registered {
+        name: name.to_owned(),
+        help: None,
+        tokenizer: tokenizer.into(),
+        detokenizer: detokenizer.into(),
+    };
+
+    let mut registry = REGISTRY.write().unwrap();
+    match registry.iter_mut().find(|r| r.name == name) {
+        Some(existing) => *existing = registered,
+        None => registry.push(registered),
+    }
+}
+
+/// Every registered tokenizer, the first being the default.
+pub fn registered() -> Vec<Registered> {
+    REGISTRY.read().unwrap().clone()
+}
+
+/// Find a registered tokenizer by its name.
+pub fn find(name: &str) -> Option<Registered> {
+    REGISTRY
+        .read()
+        .unwrap()
+        .iter()
+        .find(|registered| registered.name == name)
+        .cloned()
+}
+
+/// Splits text into words and runs of punctuation, lowercasing ASCII letters.
+/// Sentences end at punctuation starting with `.`, `!` or `?`.
+pub struct Words;
+
+impl Tokenizer for Words {
+    fn tokenize(
+        &self,
+        reader: &mut dyn BufRead,
+        sentence_markers: bool,
+    ) -> io::Result<Vec<String>> {
+        let tokens = tokenize_input(reader)?;
+        if sentence_markers {
+            Ok(mark_sentences(tokens))
+        } else {
+            Ok(tokens)
+        }
+    }
+}
+
+impl Detokenizer for Words {
+    fn detokenize(&self, tokens: &[String]) -> String {
+        format_output(tokens)
+    }
+}
+
+/// Splits text into single characters, or grapheme clusters if `graphemes` is
+/// set. Each line is a sentence, and line breaks are only kept as tokens if
+/// sentences aren't marked. Empty lines are skipped.
+pub struct Characters {
+    pub graphemes: bool,
+}
+
+impl Tokenizer for Characters {
+    fn tokenize(
+        &self,
+        reader: &mut dyn BufRead,
+        sentence_markers: bool,
+    ) -> io::Result<Vec<String>> {
+        let mut tokens = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+
+            if sentence_markers {
+                tokens.push(SENTENCE_START.to_owned());
+            } else if !tokens.is_empty() {
+                tokens.push("\n".to_owned());
+            }
+            if self.graphemes {
+                tokens.extend(line.graphemes(true).map(str::to_owned));
+            } else {
+                tokens.extend(line.chars().map(String::from));
+            }
+            if sentence_markers {
+                tokens.push(SENTENCE_END.to_owned());
+            }
+        }
+
+        Ok(tokens)
+    }
+}
+
+impl Detokenizer for Characters {
+    /// Join the characters without anything between them, putting each sentence
+    /// on its own line.
+    fn detokenize(&self, tokens: &[String]) -> String {
+        let mut output = String::new();
+        for token in tokens {
+            if token == SENTENCE_START {
+                continue;
+            } else if token == SENTENCE_END {
+                output.push('\n');
+            } else {
+                output.push_str(token);
+            }
+        }
+
+        output.truncate(output.trim_end_matches('\n').len());
+        output
+    }
+}
+
+/// Surround every sentence in `tokens` with sentence markers. Sentences end at a
+/// token starting with `.`, `!` or `?`, or at the end of the text.
+fn mark_sentences(tokens: Vec<String>) -> Vec<String> {
+    let mut marked = Vec::with_capacity(tokens.len());
+    let mut in_sentence = false;
+    for token in tokens {
+        if !in_sentence {
+            marked.push(SENTENCE_START.to_owned());
+            in_sentence = true;
+        }
+
+        let ends_sentence = token.starts_with(['.', '!', '?']);
+        marked.push(token);
+        if ends_sentence {
+            marked.push(SENTENCE_END.to_owned());
+            in_sentence = false;
+        }
+    }
+
+    if in_sentence {
+        marked.push(SENTENCE_END.to_owned());
+    }
+    marked
+}
+
+fn tokenize_input<R: BufRead + ?Sized>(reader: &mut R) -> io::Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut current_token = String::new();
+    fn finish_token(current_token: &mut String, tokens: &mut Vec<String>) {
+        if !current_token.is_empty() {
+            tokens.push(current_token.clone());
+            current_token.clear();
+        }
+    }
+
+    for ch in reader.chars() {
+        let ch = ch?;
+        if ch.is_whitespace() {
+            finish_token(&mut current_token, &mut tokens);
+        } else if ch.is_ascii_punctuation() {
+            if !current_token.ends_with(|c: char| c.is_ascii_punctuation()) {
+                finish_token(&mut current_token, &mut tokens);
+            }
+
+            current_token.push(ch.to_ascii_lowercase());
+        } else {
+            current_token.push(ch.to_ascii_lowercase());
+        }
+    }
+
+    finish_token(&mut current_token, &mut tokens);
+    Ok(tokens)
+}
+
+fn format_output(tokens: &[String]) -> String {
+    fn capitalize(word: &str) -> String {
+        let mut c = word.chars();
+        match c.next() {
+            None => String::new(),
+            Some(first) => first.to_uppercase().collect::<String>() + c.as_str(),
+        }
+    }
+
+    let mut output = String::new();
+    let mut capitalize_next = true;
+    for token in tokens {
+        if token == SENTENCE_START {
+            capitalize_next = true;
+            continue;
+        } else if token == SENTENCE_END {
+            continue;
+        }
+
+        // Add a space before this token, unless it's punctuation or the beginning of the output.
+        let first_char = token.chars().next();
+        if !(first_char.is_none_or(|c| c.is_ascii_punctuation()) || output.is_empty()) {
+            output.push(' ');
+        }
+
+        if capitalize_next {
+            capitalize_next = false;
+            output.push_str(&capitalize(token));
+        } else {
+            output.push_str(token);
+        }
+
+        if first_char.is_some_and(|c| c == '.' || c == ';' || c == '!' || c == '?') {
+            capitalize_next = true;
+        }
+    }
+
+    output
+}
@@ -0,0 +1,164 @@
+use std::fmt::Display;
+
+use crate::{MarkovGeneratorBase, StateIndex, TokenId};
+
+/// Something inconsistent found in a generator. `order` is the number of tokens
+/// in the states of the chain the problem is in.
+pub enum Problem {
+    ChainCount {
+        expected: usize,
+        found: usize,
+    },
+    NotRowMajor {
+        order: usize,
+    },
+    Shape {
+        order: usize,
+        rows: usize,
+        cols: usize,
+        states: usize,
+    },
+    StateLength {
+        order: usize,
+        state: usize,
+        length: usize,
+    },
+    UnknownToken {
+        order: usize,
+        state: usize,
+        token: TokenId,
+    },
+    IndexOutOfBounds {
+        order: usize,
+        row: usize,
+        col: usize,
+        states: usize,
+    },
+    ExplicitZero {
+        order: usize,
+        row: usize,
+        col: usize,
+    },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Problem::ChainCount { expected, found } => {
+                write!(f, "expected {expected} chains, found {found}")
+            }
+            Problem::NotRowMajor { order } => {
+                write!(f, "order {order}: the matrix isn't stored by row")
+            }
+            Problem::Shape {
+                order,
+                rows,
+                cols,
+                states,
+            } => write!(
+                f,
+                "order {order}: the matrix is {rows}x{cols}, but there are {states} states"
+            ),
+            Problem::StateLength {
+                order,
+                state,
+                length,
+            } => write!(f, "order {order}: state {state} has {length} tokens"),
+            Problem::UnknownToken {
+                order,
+                state,
+                token,
+            } => write!(
+                f,
+                "order {order}: state {state} refers to token {token}, which isn't in the \
+                 vocabulary"
+            ),
+            Problem::IndexOutOfBounds {
+                order,
+                row,
+                col,
+                states,
+            } => write!(
+                f,
+                "order {order}: transition from {row} to {col} is outside the {states} states"
+            ),
+            Problem::ExplicitZero { order, row, col } => {
+                write!(
+                    f,
+                    "order {order}: transition from {row} to {col} has a count of 0"
+                )
+            }
+        }
+    }
+}
+
+impl<S> MarkovGeneratorBase<S>
+where
+    S: StateIndex + Default,
+{
+    /// Check that the chains are consistent with each other and the vocabulary,
+    /// returning every problem found.
+    pub fn verify(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        if self.chains.len() != self.state_size as usize {
+            problems.push(Problem::ChainCount {
+                expected: self.state_size as usize,
+                found: self.chains.len(),
+            });
+        }
+
+        for (i, chain) in self.chains.iter().enumerate() {
+            let order = i + 1;
+            let n_states = chain.states.len();
+            for index in 0..n_states {
+                let state = chain.states.get_state(index).unwrap();
+                if state.len() != order {
+                    problems.push(Problem::StateLength {
+                        order,
+                        state: index,
+                        length: state.len(),
+                    });
+                }
+
+                let unknown = state.iter().find(|&&id| self.vocab.get_token(id).is_none());
+                if let Some(&token) = unknown {
+                    problems.push(Problem::UnknownToken {
+                        order,
+                        state: index,
+                        token,
+                    });
+                }
+            }
+
+            if !chain.mat.is_csr() {
+                problems.push(Problem::NotRowMajor { order });
+            }
+
+            let (rows, cols) = chain.mat.shape();
+            if rows != n_states || cols != n_states {
+                problems.push(Problem::Shape {
+                    order,
+                    rows,
+                    cols,
+                    states: n_states,
+                });
+            }
+
+            for (&count, (row, col)) in chain.mat.iter() {
+                if row >= n_states || col >= n_states {
+                    problems.push(Problem::IndexOutOfBounds {
+                        order,
+                        row,
+                        col,
+                        states: n_states,
+                    });
+                }
+                if count == 0 {
+                    problems.push(Problem::ExplicitZero { order, row, col });
+                }
+            }
+        }
+
+        problems
+    }
+}